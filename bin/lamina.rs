@@ -0,0 +1,561 @@
+//! Command-line driver for running experiments.
+//!
+//! ```text
+//! $ cargo run --release --bin lamina -- rob --samples 256 --pads 192..=256
+//! $ cargo run --release --bin lamina -- probe fp_prf --format csv
+//! $ cargo run --release --bin lamina -- pmc run -e ex_ret_instr -e r0c0 \
+//!     --body nop --count 4 --floor
+//! $ cargo run --release --bin lamina -- pmc run -e ex_ret_instr \
+//!     --asm 'add rax, 1 ; jnz skip ; nop ; skip:'
+//! $ cargo run --release --bin lamina -- run experiments/add_chain.toml
+//! $ cargo run --release --bin lamina -- events list --uarch zen3
+//! $ sudo scripts/msrcheck | cargo run --bin lamina -- decode-ctl
+//! $ cargo run --bin lamina -- env check
+//! ```
+//!
+//! Sweeps (`rob`, `prf`, `stq`, `ldq` and `probe`) use the configuration
+//! from [lamina::probe] unless some parameter is given on the command line.
+//! With the default text format, results are printed in the format expected
+//! by `scripts/plot.py`, and estimates are printed to stderr.
+
+use std::io::BufRead;
+use std::path::PathBuf;
+
+use clap::{ Args, Parser, Subcommand, ValueEnum };
+use serde::Serialize;
+
+use lamina::*;
+use lamina::asm::Block;
+use lamina::builder::TestBuilder;
+use lamina::capacity::SweepResults;
+use lamina::catalog::{ Catalog, ZEN2 };
+use lamina::cpuid::{ CpuInfo, Uarch };
+use lamina::error::{ Error, Op };
+use lamina::experiment::{ EventRecord, Experiment };
+use lamina::irq::IrqGuard;
+use lamina::mux::{ Multiplexer, MuxResults };
+use lamina::pmc::{ PerfCtl, PerfCtlDescriptor };
+use lamina::probe::{ self, Probe, PROBES };
+
+#[derive(Parser)]
+#[command(name = "lamina", about = "Microbenchmarks for AMD Zen cores")]
+struct Cli {
+    /// The core to run on (and to program counters on).
+    #[arg(short, long, global = true, default_value_t = 0)]
+    core: usize,
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Measure the size of the reorder buffer.
+    Rob(SweepArgs),
+    /// Measure the size of the integer physical register file.
+    Prf(SweepArgs),
+    /// Measure the size of the store queue.
+    Stq(SweepArgs),
+    /// Measure the size of the load queue.
+    Ldq(SweepArgs),
+    /// Run some named structure-size probes (or list them).
+    Probe {
+        names: Vec<String>,
+        #[command(flatten)]
+        sweep: SweepArgs,
+    },
+    /// Measure events with PMCs.
+    #[command(subcommand)]
+    Pmc(PmcCmd),
+    /// Run an experiment described in a TOML file (see
+    /// `lamina::experiment`).
+    Run {
+        file: PathBuf,
+        /// Only check that the experiment is valid (without running it).
+        #[arg(long)]
+        check: bool,
+        #[arg(short, long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Show the events defined for some CPU.
+    #[command(subcommand)]
+    Events(EventsCmd),
+    /// Decode raw PERF_CTL values (from the arguments, or from stdin).
+    DecodeCtl { values: Vec<String> },
+    /// Inspect the environment.
+    #[command(subcommand)]
+    Env(EnvCmd),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format { Text, Json, Csv }
+
+/// Parameters which override the configuration of a probe.
+#[derive(Args)]
+struct SweepArgs {
+    /// The number of measurements for each number of padding instructions.
+    #[arg(long)]
+    samples: Option<usize>,
+    /// The number of times the gadget is unrolled within the loop.
+    #[arg(long)]
+    unroll: Option<usize>,
+    /// The number of loop iterations.
+    #[arg(long)]
+    iters: Option<usize>,
+    /// The inclusive range of padding instructions (i.e. `0..=256`).
+    #[arg(long, value_parser = parse_range)]
+    pads: Option<(usize, usize)>,
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+#[derive(Subcommand)]
+enum PmcCmd {
+    /// Run a test body while counting some events.
+    ///
+    /// Events are multiplexed when there aren't enough counters.
+    Run(PmcRunArgs),
+}
+
+#[derive(Args)]
+struct PmcRunArgs {
+    /// An event specification (see `lamina::spec`), i.e. `ex_ret_instr`,
+    /// `r0c0`, or `event=0x76,cmask=1`.
+    #[arg(short, long = "event", required = true)]
+    events: Vec<String>,
+    /// The instruction in the test body.
+    #[arg(long, value_enum, default_value_t = Body::Nop)]
+    body: Body,
+    /// Instructions in the test body, in Intel syntax (see `lamina::asm`).
+    #[arg(long, conflicts_with_all = ["body", "asm_file"])]
+    asm: Option<String>,
+    /// A file with instructions in the test body, in Intel syntax.
+    #[arg(long, conflicts_with = "body")]
+    asm_file: Option<PathBuf>,
+    /// The number of times the test body is repeated.
+    #[arg(long, default_value_t = 4)]
+    count: usize,
+    /// The number of iterations.
+    #[arg(long, default_value_t = 0x1000)]
+    iters: usize,
+    /// The number of iterations run before measuring each group of events.
+    #[arg(long, default_value_t = 0)]
+    warmup: usize,
+    /// Measure the overhead with an empty test (a floor).
+    #[arg(long)]
+    floor: bool,
+    /// Discard iterations perturbed by interrupts.
+    #[arg(long, value_enum)]
+    discard: Option<Guard>,
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+}
+
+/// Instructions available for the body of a test.
+#[derive(Clone, Copy, ValueEnum)]
+enum Body { Nop, Add, Addps, Test, Jcc }
+impl Body {
+    fn emit(&self, asm: &mut builder::Asm) {
+        match self {
+            Body::Nop => dynasm!(asm ; nop),
+            Body::Add => dynasm!(asm ; add rax, r13),
+            Body::Addps => dynasm!(asm ; addps xmm2, xmm3),
+            Body::Test => dynasm!(asm ; test rax, rax),
+            Body::Jcc => dynasm!(asm ; test rax, rax ; jo >skip ; skip:),
+        }
+    }
+}
+
+/// See [IrqGuard].
+#[derive(Clone, Copy, ValueEnum)]
+enum Guard { Counters, Proc }
+
+#[derive(Subcommand)]
+enum EventsCmd {
+    /// List events (for the running CPU by default).
+    List {
+        #[arg(long, value_enum)]
+        uarch: Option<UarchArg>,
+        /// Load events from a directory of Linux `perf` JSON event files.
+        #[arg(long)]
+        pmu_events: Option<PathBuf>,
+        #[arg(short, long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum UarchArg { Zen2, Zen3, Zen4 }
+impl From<UarchArg> for Uarch {
+    fn from(u: UarchArg) -> Self {
+        match u {
+            UarchArg::Zen2 => Uarch::Zen2,
+            UarchArg::Zen3 => Uarch::Zen3,
+            UarchArg::Zen4 => Uarch::Zen4,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum EnvCmd {
+    /// Check the system configuration for the target core.
+    Check,
+}
+
+/// Parse an inclusive range (i.e. `0..=256` or `0-256`).
+fn parse_range(s: &str) -> Result<(usize, usize), String> {
+    let (lo, hi) = s.split_once("..=").or_else(|| s.split_once('-'))
+        .ok_or_else(|| format!("expected a range like 0..=256, got '{}'", s))?;
+    let lo: usize = lo.parse().map_err(|e| format!("'{}': {}", lo, e))?;
+    let hi: usize = hi.parse().map_err(|e| format!("'{}': {}", hi, e))?;
+    if lo > hi {
+        return Err(format!("empty range '{}'", s));
+    }
+    Ok((lo, hi))
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(&cli) {
+        eprintln!("[!] {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), lamina::Error> {
+    match &cli.cmd {
+        Cmd::Rob(args) => sweep(cli.core, &probe::ROB, args),
+        Cmd::Prf(args) => sweep(cli.core, &probe::PRF, args),
+        Cmd::Stq(args) => sweep(cli.core, &probe::STQ, args),
+        Cmd::Ldq(args) => sweep(cli.core, &probe::LDQ, args),
+        Cmd::Probe { names, sweep: args } => {
+            if names.is_empty() {
+                list_probes();
+                return Ok(());
+            }
+            let mut probes = Vec::new();
+            for name in names.iter() {
+                match Probe::get(name) {
+                    Some(p) => probes.push(p),
+                    None => {
+                        eprintln!("[!] unknown probe '{}'", name);
+                        list_probes();
+                        std::process::exit(1);
+                    },
+                }
+            }
+            for p in probes {
+                sweep(cli.core, p, args)?;
+            }
+            Ok(())
+        },
+        Cmd::Pmc(PmcCmd::Run(args)) => pmc_run(cli.core, args),
+        Cmd::Run { file, check, format } => {
+            run_experiment(cli.core, file, *check, *format)
+        },
+        Cmd::Events(EventsCmd::List { uarch, pmu_events, format }) => {
+            events_list(*uarch, pmu_events.as_ref(), *format)
+        },
+        Cmd::DecodeCtl { values } => {
+            decode_ctl(values);
+            Ok(())
+        },
+        Cmd::Env(EnvCmd::Check) => {
+            env_check(cli.core);
+            Ok(())
+        },
+    }
+}
+
+fn list_probes() {
+    println!("{:<10} {:>5}  description", "probe", "zen2");
+    for p in PROBES.iter() {
+        let zen2 = p.zen2.map(|s| s.to_string())
+            .unwrap_or_else(|| "?".to_string());
+        println!("{:<10} {:>5}  {}", p.name, zen2, p.desc);
+    }
+}
+
+/// Run a probe (with parameters from the command line).
+fn sweep(core: usize, probe: &Probe, args: &SweepArgs)
+    -> Result<(), lamina::Error>
+{
+    let mut probe = probe.clone();
+    if let Some(n) = args.samples { probe.samples = n; }
+    if let Some(n) = args.unroll { probe.unroll = n; }
+    if let Some(n) = args.iters { probe.iters = n; }
+    if let Some((lo, hi)) = args.pads { probe.pads = lo..=hi; }
+
+    lamina::util::pin_to_core(core)?;
+    let res = probe.run()?;
+    match args.format {
+        Format::Text => probe.report(&res),
+        Format::Json => println!("{}", sweep_json(&probe, &res)),
+        Format::Csv => {
+            println!("probe,pad,min,mean,max,median,stddev");
+            for p in res.points.iter() {
+                let s = &p.summary;
+                println!("{},{},{},{},{},{},{}", probe.name, p.pad, s.min,
+                    s.mean, s.max, s.median, s.stddev);
+            }
+        },
+    }
+    Ok(())
+}
+
+fn sweep_json(probe: &Probe, res: &SweepResults) -> String {
+    #[derive(Serialize)]
+    struct Point { pad: usize, min: f64, mean: f64, max: f64, median: f64,
+        stddev: f64 }
+    #[derive(Serialize)]
+    struct Knee { size: usize, range: (usize, usize), low: f64, high: f64,
+        snr: f64, confident: bool }
+    #[derive(Serialize)]
+    struct Record { probe: &'static str, zen2: Option<usize>,
+        knee: Option<Knee>, points: Vec<Point> }
+
+    let record = Record {
+        probe: probe.name,
+        zen2: probe.zen2,
+        knee: res.knee().map(|k| Knee {
+            size: k.size, range: k.range, low: k.low, high: k.high,
+            // JSON has no infinity
+            snr: if k.snr.is_finite() { k.snr } else { f64::MAX },
+            confident: k.is_confident(),
+        }),
+        points: res.points.iter().map(|p| Point {
+            pad: p.pad, min: p.summary.min, mean: p.summary.mean,
+            max: p.summary.max, median: p.summary.median,
+            stddev: p.summary.stddev,
+        }).collect(),
+    };
+    serde_json::to_string_pretty(&record).unwrap()
+}
+
+/// Count some events while running a test.
+fn pmc_run(core: usize, args: &PmcRunArgs) -> Result<(), lamina::Error> {
+    let (events, ctls) = lamina::spec::parse_events(&args.events)?;
+
+    let block = match (&args.asm, &args.asm_file) {
+        (Some(text), _) => Some(Block::assemble(text)?),
+        (None, Some(path)) => Some(Block::read(path)?),
+        (None, None) => None,
+    };
+
+    lamina::util::pin_to_core(core)?;
+    let mut ctx = lamina::ctx::open_backend_on(core)?;
+
+    let body = args.body;
+    let code = TestBuilder::pmc()?
+        .prologue()
+        .start()
+        .repeat(args.count, |asm| match &block {
+            Some(block) => block.emit(asm),
+            None => body.emit(asm),
+        })
+        .stop()
+        .epilogue()
+        .finish()?;
+    let mut test = PMCTest::from_compiled("pmc run", &code,
+        &PerfCtlDescriptor::new());
+    if args.floor {
+        test = test.with_floor()?;
+    }
+    match args.discard {
+        Some(Guard::Counters) => {
+            test = test.discard_interrupts(IrqGuard::Counters);
+        },
+        Some(Guard::Proc) => {
+            test = test.discard_interrupts(IrqGuard::ProcInterrupts);
+        },
+        None => {},
+    }
+
+    let mux = Multiplexer::from_ctls(&events, &ctls, test.source.counters())?
+        .warmup(args.warmup);
+    let res = mux.run(ctx.as_mut(), &mut test, args.iters)?;
+    match args.format {
+        Format::Text => res.print(),
+        Format::Json => println!("{}", mux_json(&res)),
+        Format::Csv => {
+            println!("event,group,counter,ctl,sample,value");
+            for r in res.events.iter() {
+                for (idx, val) in r.data.iter().enumerate() {
+                    println!("\"{}\",{},{},{:#x},{},{}", r.event, r.group,
+                        r.counter, r.ctl.0, idx, val);
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Run an experiment from some file.
+fn run_experiment(core: usize, file: &PathBuf, check: bool, format: Format)
+    -> Result<(), lamina::Error>
+{
+    let exp = Experiment::read(file)?;
+    exp.check()?;
+    if check {
+        eprintln!("[*] {}: {} events, {} points", exp.name, exp.events.len(),
+            exp.points().len());
+        return Ok(());
+    }
+
+    lamina::util::pin_to_core(core)?;
+    let mut ctx = lamina::ctx::open_backend_on(core)?;
+    let res = exp.run(ctx.as_mut())?;
+    match format {
+        Format::Text => res.print(),
+        Format::Json => println!("{}", res.to_json()),
+        Format::Csv => {
+            res.write_csv_to(std::io::stdout()).map_err(|e| Error::Io {
+                op: Op::WriteFile, path: "<stdout>".to_string(), err: e.into()
+            })?;
+        },
+    }
+    Ok(())
+}
+
+fn mux_json(res: &MuxResults) -> String {
+    let records: Vec<EventRecord> = res.events.iter()
+        .map(EventRecord::new)
+        .collect();
+    serde_json::to_string_pretty(&records).unwrap()
+}
+
+fn events_list(uarch: Option<UarchArg>, pmu_events: Option<&PathBuf>,
+    format: Format) -> Result<(), lamina::Error>
+{
+    let uarch = match uarch {
+        Some(u) => Uarch::from(u),
+        None => Catalog::native()?.uarch,
+    };
+    let owned;
+    let catalog = match pmu_events {
+        Some(dir) => {
+            owned = Catalog::from_pmu_events(uarch, dir)?;
+            &owned
+        },
+        None => Catalog::for_uarch(uarch),
+    };
+
+    match format {
+        Format::Text => {
+            println!("# {} events (from {})", catalog.uarch, catalog.source);
+            for def in catalog.events.iter() {
+                println!("PMCx{:03x} {:<32} {}", def.select, def.name,
+                    def.desc);
+                for m in def.umasks.iter() {
+                    println!("  {:#04x} {:<30} {}", m.mask, m.name, m.desc);
+                }
+            }
+        },
+        Format::Json => {
+            #[derive(Serialize)]
+            struct Mask { name: &'static str, mask: u8, desc: &'static str }
+            #[derive(Serialize)]
+            struct Def { name: &'static str, select: u16,
+                unit: Option<&'static str>, desc: &'static str,
+                umasks: Vec<Mask> }
+            let defs: Vec<Def> = catalog.events.iter().map(|d| Def {
+                name: d.name, select: d.select, unit: d.unit, desc: d.desc,
+                umasks: d.umasks.iter().map(|m| Mask {
+                    name: m.name, mask: m.mask, desc: m.desc
+                }).collect(),
+            }).collect();
+            println!("{}", serde_json::to_string_pretty(&defs).unwrap());
+        },
+        Format::Csv => {
+            println!("select,event,umask,name");
+            for def in catalog.events.iter() {
+                println!("{:#05x},{},,", def.select, def.name);
+                for m in def.umasks.iter() {
+                    println!("{:#05x},{},{:#04x},{}", def.select, def.name,
+                        m.mask, m.name);
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Decode raw `PERF_CTL` values.
+///
+/// Values are hexadecimal (with or without a `0x` prefix, like the output
+/// from `rdmsr`).
+fn decode_ctl(values: &[String]) {
+    let catalog = match Catalog::installed() {
+        Some(cat) => cat,
+        None => Catalog::native().unwrap_or_else(|e| {
+            eprintln!("[!] {}, using Zen 2 events", e);
+            &ZEN2
+        }),
+    };
+
+    let mut values = values.to_vec();
+    if values.is_empty() {
+        values = std::io::stdin().lock().lines()
+            .map(|line| line.expect("couldn't read stdin"))
+            .collect();
+    }
+    for val in values.iter().map(|v| v.trim()).filter(|v| !v.is_empty()) {
+        let hex = val.strip_prefix("0x").unwrap_or(val);
+        match usize::from_str_radix(hex, 16) {
+            Ok(raw) => println!("{}", PerfCtl(raw).decode(catalog)),
+            Err(e) => eprintln!("[!] invalid value '{}': {}", val, e),
+        }
+    }
+}
+
+/// Check the settings changed by `scripts/config-cpu`, and whether the
+/// counters can be used.
+fn env_check(core: usize) {
+    let read = |path: &str| {
+        std::fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+    };
+    let check = |ok: bool, msg: String| {
+        println!("[{}] {}", if ok { "+" } else { "!" }, msg);
+    };
+
+    let cpu = CpuInfo::get();
+    match cpu.uarch() {
+        Some(uarch) => check(true, format!("CPU: {} ({})", cpu, uarch)),
+        None => check(false, format!("CPU: {} (no known events)", cpu)),
+    }
+    println!("[*] counter width: {} bits", cpuid::counter_width());
+
+    match lamina::util::pin_to_core(core) {
+        Ok(()) => check(true, format!("pinned to core {}", core)),
+        Err(e) => check(false, e.to_string()),
+    }
+    match lamina::ctx::PMCContext::on_core(core) {
+        Ok(_) => check(true, format!("kernel module: {} (core {})",
+            lamina::ctx::PMCContext::CHARDEV, core)),
+        Err(e) => check(false, format!("kernel module: {}", e)),
+    }
+
+    let settings: [(&str, String, fn(&str) -> bool, &str); 6] = [
+        ("perf_event_paranoid", "/proc/sys/kernel/perf_event_paranoid".into(),
+            |v| v.parse::<i32>().is_ok_and(|v| v <= 2),
+            "perf_event_open() is restricted"),
+        ("rdpmc", "/sys/bus/event_source/devices/cpu/rdpmc".into(),
+            |v| v != "0", "RDPMC is disabled"),
+        ("SMT", "/sys/devices/system/cpu/smt/active".into(),
+            |v| v == "0", "SMT is enabled"),
+        ("NMI watchdog", "/proc/sys/kernel/nmi_watchdog".into(),
+            |v| v == "0", "the NMI watchdog uses a counter"),
+        ("boost", "/sys/devices/system/cpu/cpufreq/boost".into(),
+            |v| v == "0", "frequency boosting is enabled"),
+        ("governor", format!("/sys/devices/system/cpu/cpufreq/policy{}/\
+            scaling_governor", core),
+            |v| v == "performance", "the frequency may change"),
+    ];
+    for (name, path, ok, warning) in settings.iter() {
+        match read(path) {
+            Some(v) if ok(&v) => check(true, format!("{}: {}", name, v)),
+            Some(v) => check(false, format!("{}: {} ({}, see \
+                scripts/config-cpu)", name, v, warning)),
+            None => println!("[?] {}: {} is unavailable", name, path),
+        }
+    }
+}
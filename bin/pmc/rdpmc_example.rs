@@ -1,17 +1,18 @@
 //! Playing with PMCs and measuring speculative events.
 
 use lamina::*;
-use lamina::ctx::PMCContext;
 use lamina::pmc::PerfCtlDescriptor;
 use lamina::event::Event;
+use lamina::irq::IrqGuard;
 
 
-fn main() -> Result<(), &'static str> {
-    // The kernel module always instruments PMCs on core 0
-    lamina::util::pin_to_core(0);
+fn main() -> Result<(), lamina::Error> {
+    // The kernel module instruments PMCs on core 0 by default
+    lamina::util::pin_to_core(0)?;
 
-    // Context for interactions with the kernel module
-    let mut ctx = PMCContext::new()?;
+    // Context for interactions with the kernel module (or perf_event_open()
+    // if the kernel module isn't loaded)
+    let mut ctx = lamina::ctx::open_backend()?;
     let pmc = PerfCtlDescriptor::new()
         .set(0, Event::ExRetCops(0x00))
         .set(1, Event::DeSrcOpDisp(0x03))
@@ -21,23 +22,19 @@ fn main() -> Result<(), &'static str> {
         .set(5, Event::LsSmiRx(0x00));
     ctx.write(&pmc)?;
 
-    let code = emit_rdpmc_test_all!();
-    let mut test = PMCTest::new("floor", &code, &pmc);
-    test.run_iter(0x1000);
-    test.print();
-    println!("");
-
+    // The floor (an empty test) is run alongside the test, and the median 
+    // from the floor is subtracted from the results
     let code = emit_rdpmc_test_all!(
         ; nop
         ; nop
         ; nop
         ; nop
-    );
-    let mut test = PMCTest::new("4 nops", &code, &pmc);
-    test.run_iter(0x1000);
+    )?;
+    // Iterations where an interrupt or SMI was counted are discarded
+    let mut test = PMCTest::new("4 nops", &code, &pmc).with_floor()?
+        .discard_interrupts(IrqGuard::Counters);
+    test.run_iter(ctx.as_ref(), 0x1000)?;
     test.print();
 
-
-
     Ok(())
 }
@@ -1,44 +1,44 @@
 //! Playing with PMCs and measuring speculative events.
 
 use lamina::*;
-use lamina::ctx::PMCContext;
 use lamina::pmc::PerfCtlDescriptor;
 use lamina::event::Event;
+use lamina::stats::Samples;
 
-fn minmax(data: &Vec<usize>) -> (usize, usize) {
-    let min = data.iter().min().unwrap();
-    let max = data.iter().max().unwrap();
-    (*min, *max)
-}
-
+fn main() -> Result<(), lamina::Error> {
+    // The kernel module instruments PMCs on core 0 by default
+    lamina::util::pin_to_core(0)?;
 
-fn main() -> Result<(), &'static str> {
-    // The kernel module always instruments PMCs on core 0
-    lamina::util::pin_to_core(0);
-
-    // Context for interactions with the kernel module
-    let mut ctx = PMCContext::new()?;
-    let mut pmc = PerfCtlDescriptor::new()
+    // Context for interactions with the kernel module (or perf_event_open()
+    // if the kernel module isn't loaded)
+    let mut ctx = lamina::ctx::open_backend()?;
+    let pmc = PerfCtlDescriptor::new()
         .set(0, Event::LsRdTsc(0x00));
-    ctx.write(&pmc);
+    ctx.write(&pmc)?;
+
+    // The counter used for LsRdTsc might not be PERF_CTL[0] with perf
+    let ctr = ctx.rdpmc_index(0).ok_or(lamina::Error::Unsupported {
+        op: lamina::error::Op::ReadCtr, 
+        reason: "counter 0 isn't readable with RDPMC",
+    })?;
 
     // Scratch pointer for emitted code
-    let mut scratch = Box::new([0u8; 64]);
+    let scratch = Box::new([0u8; 64]);
     let scratch_ptr = scratch.as_ptr();
 
     // Get the number of ambient events for emit_rdpmc_test_single!().
     // You should see no LsRdTsc events.
-    let test = emit_rdpmc_test_single!(0, );
+    let test = emit_rdpmc_test_single!(ctr, )?;
     let mut res = Vec::new();
     for _ in 0..0x2000 {
         res.push(run_simple_test(&test));
     }
-    let res = minmax(&res);
-    println!("floor      min={} max={}", res.0, res.1);
+    let res = res.summary();
+    println!("floor      {}", res);
 
     // Run a test where RDTSC is executed speculatively.
     // You should see at most 1 LsRdTsc event. 
-    let test = emit_rdpmc_test_single!(0, 
+    let test = emit_rdpmc_test_single!(ctr, 
         ; mov rdi, QWORD scratch_ptr as _
         ; call ->func
 
@@ -55,17 +55,17 @@ fn main() -> Result<(), &'static str> {
         ; mov [rdi], rdx
         ; mfence
         ; nop
-    );
+    )?;
     let mut res = Vec::new();
     for _ in 0..0x2000 {
         res.push(run_simple_test(&test));
     }
-    let res = minmax(&res);
-    println!("spec_rdtsc min={} max={}", res.0, res.1);
+    let res = res.summary();
+    println!("spec_rdtsc {}", res);
 
     // Run a test where a #UD stops speculation before reaching RDTSC.
     // You should see no LsRdTsc events.
-    let test = emit_rdpmc_test_single!(0,
+    let test = emit_rdpmc_test_single!(ctr,
         ; mov rdi, QWORD scratch_ptr as _
         ; call ->func
 
@@ -83,17 +83,17 @@ fn main() -> Result<(), &'static str> {
         ; mov [rdi], rdx
         ; mfence
         ; nop
-    );
+    )?;
     let mut res = Vec::new();
     for _ in 0..0x2000 {
         res.push(run_simple_test(&test));
     }
-    let res = minmax(&res);
-    println!("spec_#ud   min={} max={}", res.0, res.1);
+    let res = res.summary();
+    println!("spec_#ud   {}", res);
 
     // Run a test where #GP stops speculation before reaching RDTSC.
     // You should see no LsRdTsc events.
-    let test = emit_rdpmc_test_single!(0,
+    let test = emit_rdpmc_test_single!(ctr,
         ; mov rdi, QWORD scratch_ptr as _
         ; call ->func
 
@@ -112,13 +112,13 @@ fn main() -> Result<(), &'static str> {
         ; mov [rdi], rdx
         ; mfence
         ; nop
-    );
+    )?;
     let mut res = Vec::new();
     for _ in 0..0x2000 {
         res.push(run_simple_test(&test));
     }
-    let res = minmax(&res);
-    println!("spec_#gp   min={} max={}", res.0, res.1);
+    let res = res.summary();
+    println!("spec_#gp   {}", res);
 
     Ok(())
 }
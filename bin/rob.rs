@@ -64,67 +64,20 @@
 //! [^2]: [travisdowns/robsize](https://github.com/travisdowns/robsize)
 //!
 
-use lamina::*;
-use lamina::x86::*;
-use lamina::util::*;
-use lamina::chase::*;
+use lamina::probe;
 
-/// The number of measurements taken per-test.
-const SAMPLES: usize = 512;
-
-/// Number of times the gadget is unrolled within the loop.
-const UNROLL: usize  = 32;
-
-/// Number of loop iterations.
-const ITER: usize    = 0x80;
-
-fn main() {
+fn main() -> Result<(), lamina::Error> {
 
     // NOTE: You probably want to run this with simultaneous multithreading 
     // (SMT) disabled, so that we always schedule this process on a single 
     // hardware thread. See [scripts/config-cpu].
 
-    pin_to_core(0);
-
-    // Create a random cyclic array of linked pointers, for deliberately
-    // invoking loads that reliably miss in the L1 cache. 
-    //
-    // With a stride of 512 (8-byte pointers), each successive reference in
-    // the chain should be separated by a page (512 * 8 = 4096 bytes).
-
-    let mut rng = Xorshift64::new();
-    let mut mem = PointerMaze::<0x1000_0000>::new();
-    mem.shuffle(&mut rng, 512);
-    mem.flush();
-
-    let ptr_a = mem.head_ptr() as *const usize;
-    let ptr_b = mem.mid_ptr() as *const usize;
-    let ptr_c = 0 as *const usize;
+    lamina::util::pin_to_core(0)?;
 
-    for num_pad in 0..=256 {
-        mem.flush();
-        let mut res = [0usize; SAMPLES];
-
-        // This is a macro that generates the gadget; see [src/codegen.rs]
-        let test = emit_hwong_gadget_test!(
-            ptr_a, ptr_b, ptr_c, ITER, UNROLL, num_pad,
-            body_a(; nop),
-            body_b(; nop)
-        );
-
-        for i in 0..SAMPLES {
-            res[i] = run_simple_test(&test);
-        }
-
-        let min = *res.iter().min().unwrap() as f64
-            / ITER as f64 / UNROLL as f64;
-        let avg = res.iter().sum::<usize>() as f64
-            / ITER as f64 / UNROLL as f64 / SAMPLES as f64;
-        let max = *res.iter().max().unwrap() as f64
-            / ITER as f64 / UNROLL as f64 ;
-
-        println!("{:03}: min={:.3} avg={:.3} max={:.3}", 
-                 num_pad, min, avg, max);
-    }
+    // This sweeps the number of padding instructions through the gadget;
+    // see [src/capacity.rs] and [src/probe.rs]. The estimated size is 
+    // printed to stderr.
+    let res = probe::ROB.run()?;
+    probe::ROB.report(&res);
+    Ok(())
 }
-
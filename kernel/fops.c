@@ -2,13 +2,13 @@
 
 #include <linux/smp.h>
 #include <linux/fs.h>
+#include <linux/slab.h>
+#include <linux/cpumask.h>
 #include <asm/msr.h>
 
 #include "lamina.h"
 #include "fops.h"
 
-struct lamina_msg msg;
-
 void write_pmcs(void* info)
 {
 	struct lamina_msg *msg = info;
@@ -55,16 +55,75 @@ void write_pmcs(void* info)
 	return;
 }
 
+void read_pmcs(void* info)
+{
+	struct lamina_msg *msg = info;
+
+	rdmsrl(0xc0010200, msg->ctl[0]);
+	rdmsrl(0xc0010202, msg->ctl[1]);
+	rdmsrl(0xc0010204, msg->ctl[2]);
+	rdmsrl(0xc0010206, msg->ctl[3]);
+	rdmsrl(0xc0010208, msg->ctl[4]);
+	rdmsrl(0xc001020a, msg->ctl[5]);
+}
+
+int lamina_open(struct inode *inode, struct file *file)
+{
+	struct lamina_file *state = kzalloc(sizeof(struct lamina_file), 
+			GFP_KERNEL);
+	if (!state) {
+		return -ENOMEM;
+	}
+	state->cpu = TARGET_CPU;
+	file->private_data = state;
+	return 0;
+}
+
+int lamina_release(struct inode *inode, struct file *file)
+{
+	kfree(file->private_data);
+	return 0;
+}
+
 long int lamina_ioctl(struct file *file, unsigned int cmd, unsigned long arg)
 {
 	long int res = -EINVAL;
+	struct lamina_file *state = file->private_data;
+	struct lamina_msg out;
 
 	switch (cmd) {
 	case LAMINA_CMD_WRITECTL:
-		res = copy_from_user(&msg, (struct lamina_msg *)arg, 
+		res = copy_from_user(&state->msg, (struct lamina_msg *)arg, 
 				sizeof(struct lamina_msg)
 		);
-		smp_call_function_single(TARGET_CPU, write_pmcs, (void*)&msg, true);
+		smp_call_function_single(state->cpu, write_pmcs, 
+				(void*)&state->msg, true);
+		state->written = true;
+		break;
+	case LAMINA_CMD_READCTL:
+		smp_call_function_single(state->cpu, read_pmcs, (void*)&out, true);
+		res = copy_to_user((struct lamina_msg *)arg, &out, 
+				sizeof(struct lamina_msg)
+		);
+		break;
+	case LAMINA_CMD_SETCPU:
+		if (arg >= nr_cpu_ids || !cpu_online(arg)) {
+			break;
+		}
+		// Stop the counters on the previous CPU (only if they were 
+		// written by us, since they may be in use by someone else)
+		if (state->written) {
+			memset(&state->msg, 0, sizeof(struct lamina_msg));
+			smp_call_function_single(state->cpu, write_pmcs, 
+					(void*)&state->msg, true);
+			state->written = false;
+		}
+		if (init_pmcs(arg) != 0) {
+			res = -EBUSY;
+			break;
+		}
+		state->cpu = arg;
+		res = 0;
 		break;
 	default:
 		break;
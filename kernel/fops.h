@@ -3,6 +3,8 @@
 #ifndef _FOPS_H
 #define _FOPS_H
 
+int lamina_open(struct inode *inode, struct file *file);
+int lamina_release(struct inode *inode, struct file *file);
 long int lamina_ioctl(struct file *file, unsigned int cmd, unsigned long arg);
 ssize_t lamina_read(struct file *file, char __user *buf, size_t count,
 		loff_t *fpos);
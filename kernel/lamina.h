@@ -4,11 +4,25 @@
 #include <linux/types.h>
 #include <linux/sched.h>
 
+// The default target CPU for a newly-opened file descriptor
 #define TARGET_CPU 0
+
 #define LAMINA_CMD_WRITECTL 0x00001000
+#define LAMINA_CMD_READCTL  0x00001001
+#define LAMINA_CMD_SETCPU   0x00001002
 
 struct lamina_msg {
 	__u64 ctl[6];
 };
 
+// State associated with an open file descriptor
+struct lamina_file {
+	int cpu;
+	// Whether the counters on 'cpu' have been written by this file
+	bool written;
+	struct lamina_msg msg;
+};
+
+int init_pmcs(int cpu);
+
 #endif // _LAMINA_H
@@ -13,6 +13,8 @@
 
 static const struct file_operations lamina_fops = {
 	.owner				= THIS_MODULE,
+	.open				= lamina_open,
+	.release			= lamina_release,
 	.unlocked_ioctl		= lamina_ioctl,
 };
 
@@ -31,26 +33,27 @@ static u32 PERF_CTR_MSR[6] = {
 };
 
 
-// Zero out any PERF_CTL/PERF_CTR pairs that are disabled.
+// Zero out any PERF_CTL/PERF_CTR pairs that are disabled on some CPU.
 // If any of the counters are enabled, return an error.
-int init_pmcs(void)
+int init_pmcs(int cpu)
 {
 	u64 val;
 	int err, i;
 	for (i = 0; i < 6; i++)
 	{
-		err = rdmsrl_safe_on_cpu(TARGET_CPU, PERF_CTL_MSR[i], &val);
+		err = rdmsrl_safe_on_cpu(cpu, PERF_CTL_MSR[i], &val);
 		if (err) {
 			pr_err("lamina: invalid msr %08x (?)\n", PERF_CTL_MSR[i]);
 			return -1;
 		} else {
 			if ((val & (1 << 22)) != 0) {
-				pr_err("lamina: PERF_CTL[%d] is enabled\n", i);
+				pr_err("lamina: PERF_CTL[%d] is enabled on cpu %d\n", 
+						i, cpu);
 				pr_err("lamina: all counters must be disabled\n");
 				return -1;
 			} else {
-				wrmsrl_safe_on_cpu(TARGET_CPU, PERF_CTL_MSR[i], 0);
-				wrmsrl_safe_on_cpu(TARGET_CPU, PERF_CTR_MSR[i], 0);
+				wrmsrl_safe_on_cpu(cpu, PERF_CTL_MSR[i], 0);
+				wrmsrl_safe_on_cpu(cpu, PERF_CTR_MSR[i], 0);
 			}
 		}	
 	}
@@ -72,7 +75,7 @@ static __init int lamina_init(void)
 		return -1;
 	}
 
-	if (init_pmcs() != 0) {
+	if (init_pmcs(TARGET_CPU) != 0) {
 		return -1;
 	}
 	if (misc_register(&lamina_dev) != 0) {
@@ -0,0 +1,607 @@
+//! Assembling test bodies from Intel-syntax text at runtime.
+//!
+//! Unlike the `dynasm!` macro, this lets you write the body of a test in
+//! a file (or on the command line) without recompiling anything. Each
+//! statement is parsed into an iced-x86 [Instruction] (choosing the
+//! shortest encoding that matches the operands), and the result is encoded
+//! as a position-independent [Block] which can be emitted into any test.
+//!
+//! ```no_run
+//! use lamina::*;
+//! use lamina::asm::Block;
+//! use lamina::builder::TestBuilder;
+//!
+//! fn main() -> Result<(), lamina::Error> {
+//!     let block = Block::assemble("add rax, rbx ; mov [rsi], rax")?;
+//!     let test = TestBuilder::pmc()?
+//!         .prologue()
+//!         .start()
+//!         .repeat(4, |asm| block.emit(asm))
+//!         .stop()
+//!         .epilogue()
+//!         .finish()?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! ## Syntax
+//!
+//! - Statements are separated by newlines or `;`, and `#` starts a comment
+//! - Labels are defined with `name:`, and can only be used as branch
+//!   targets within the same block (a label at the end of the block refers
+//!   to the end of the last instruction)
+//! - Memory operands are written as `[base + index*scale + disp]`, with an
+//!   optional size (i.e. `qword ptr`) which is required when the size
+//!   can't be inferred from the other operands
+//! - Immediates are decimal, or hexadecimal with `0x` or `h`
+//! - Condition codes may use any of the usual aliases (i.e. `jz`, `setc`)
+//!
+//! Instructions are free to use any register, but keep in mind that the
+//! surrounding test also uses some registers (see [crate::builder]).
+//!
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use dynasmrt::dynasm;
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Code, Encoder, IcedError, Instruction,
+    InstructionBlock, MemoryOperand, Mnemonic, OpCodeOperandKind, Register,
+    RepPrefixKind,
+};
+
+use crate::builder::Asm;
+use crate::error::{ Error, Op };
+
+type Err<T> = Result<T, Error>;
+
+/// Address of the first instruction in a block before encoding. Each
+/// instruction is given a distinct address, so that branches can refer to
+/// the instruction following a label.
+const BASE_IP: u64 = 0x1000;
+
+/// Sizes of memory operands (in bytes).
+const SIZES: &[(&str, usize)] = &[
+    ("byte", 1), ("word", 2), ("dword", 4), ("fword", 6), ("qword", 8),
+    ("tbyte", 10), ("tword", 10), ("xmmword", 16), ("oword", 16),
+    ("ymmword", 32), ("zmmword", 64),
+];
+
+/// Aliases for condition codes, and the names used by iced-x86.
+const CONDITIONS: &[(&str, &str)] = &[
+    ("z", "e"), ("nz", "ne"), ("c", "b"), ("nae", "b"), ("nc", "ae"),
+    ("nb", "ae"), ("na", "be"), ("nbe", "a"), ("nge", "l"), ("nl", "ge"),
+    ("ng", "le"), ("nle", "g"), ("pe", "p"), ("po", "np"),
+];
+
+/// A sequence of assembled instructions.
+#[derive(Clone, Debug)]
+pub struct Block {
+    /// The parsed instructions.
+    pub insts: Vec<Instruction>,
+    /// The encoded instructions. Branches are relative to the start of the
+    /// block, so these can be emitted anywhere (and repeated).
+    pub bytes: Vec<u8>,
+}
+impl Block {
+    /// Assemble some Intel-syntax text.
+    ///
+    /// ```
+    /// use lamina::asm::Block;
+    /// let block = Block::assemble("
+    ///     add rax, 1      # sets ZF when RAX wraps around
+    ///     jnz skip
+    ///     nop
+    /// skip:
+    ///     mov qword ptr [rdi + 8], rsi
+    /// ").unwrap();
+    /// assert_eq!(block.insts.len(), 4);
+    /// assert_eq!(block.bytes, [
+    ///     0x48, 0x83, 0xc0, 0x01,
+    ///     0x75, 0x01,
+    ///     0x90,
+    ///     0x48, 0x89, 0x77, 0x08,
+    /// ]);
+    ///
+    /// // Labels may also be at the end of the block
+    /// let block = Block::assemble("add rax, 1 ; jnz skip ; nop ; skip:")
+    ///     .unwrap();
+    /// assert_eq!(block.insts.len(), 3);
+    /// assert_eq!(block.bytes, [0x48, 0x83, 0xc0, 0x01, 0x75, 0x01, 0x90]);
+    /// ```
+    pub fn assemble(text: &str) -> Err<Self> {
+        // Labels, with the index of the following instruction and the line
+        // where each label is defined
+        let mut labels: Vec<(String, usize, usize)> = Vec::new();
+        let mut stmts: Vec<Stmt> = Vec::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            for text in line.split(';') {
+                let text = text.trim().to_lowercase();
+                let err = |reason: String| Error::InvalidAsm {
+                    line: idx + 1, text: text.clone(), reason
+                };
+                let mut rest = text.as_str();
+                while let Some((name, tail)) = split_label(rest) {
+                    if labels.iter().any(|(x, ..)| x == name) {
+                        return Err(err(format!("label '{}' is already \
+                            defined", name)));
+                    }
+                    labels.push((name.to_string(), stmts.len(), idx + 1));
+                    rest = tail.trim_start();
+                }
+                if rest.is_empty() {
+                    continue;
+                }
+                let stmt = Stmt::parse(idx + 1, rest).map_err(err)?;
+                stmts.push(stmt);
+            }
+        }
+        let ip = |idx: usize| BASE_IP + 16 * idx as u64;
+        let mut insts = Vec::new();
+        for (idx, stmt) in stmts.iter().enumerate() {
+            let target = match stmt.ops.as_slice() {
+                [Operand::Label(name)] => {
+                    match labels.iter().find(|(x, ..)| x == name) {
+                        Some((_, pos, _)) => Some(ip(*pos)),
+                        None => return Err(stmt.error(format!("undefined \
+                            label '{}'", name))),
+                    }
+                },
+                _ => None,
+            };
+            let mut inst = stmt.select(target)
+                .map_err(|reason| stmt.error(reason))?;
+            inst.set_ip(ip(idx));
+            insts.push(inst);
+        }
+
+        // Labels at the end of the block refer to a zero-length marker
+        // just past the last instruction (which is dropped after encoding)
+        let marker = labels.iter().any(|(_, pos, _)| *pos == stmts.len());
+        if marker {
+            let mut inst = Instruction::with(Code::Zero_bytes);
+            inst.set_ip(ip(stmts.len()));
+            insts.push(inst);
+        }
+        let block = InstructionBlock::new(&insts, 0);
+        let res = BlockEncoder::encode(64, block, BlockEncoderOptions::NONE)
+            .map_err(|e| Error::Codegen {
+                op: Op::Assemble, reason: e.to_string()
+            })?;
+        if marker {
+            insts.pop();
+        }
+        Ok(Self { insts, bytes: res.code_buffer })
+    }
+
+    /// Assemble the contents of some file (see [Block::assemble]).
+    pub fn read(path: impl AsRef<Path>) -> Err<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|err| Error::Io {
+            op: Op::ReadFile, path: path.display().to_string(), err
+        })?;
+        Self::assemble(&text)
+    }
+
+    /// Emit the encoded instructions.
+    pub fn emit(&self, asm: &mut Asm) {
+        dynasm!(asm ; .bytes self.bytes.iter());
+    }
+
+    /// Return the size of the encoded instructions in bytes.
+    pub fn len(&self) -> usize { self.bytes.len() }
+
+    /// Returns true if there are no instructions.
+    pub fn is_empty(&self) -> bool { self.bytes.is_empty() }
+}
+impl std::str::FromStr for Block {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::assemble(s)
+    }
+}
+
+/// An operand to some instruction.
+#[derive(Clone, Debug)]
+enum Operand {
+    Reg(Register),
+    Mem(MemoryOperand),
+    /// An immediate which fits in an `i32`.
+    Imm(i32),
+    /// An immediate which only fits in a `u32`.
+    UImm(u32),
+    /// A 64-bit immediate.
+    Imm64(i64),
+    Label(String),
+}
+
+/// A single parsed statement.
+#[derive(Clone, Debug)]
+struct Stmt {
+    line: usize,
+    text: String,
+    mnemonic: Mnemonic,
+    lock: bool,
+    rep: bool,
+    repne: bool,
+    ops: Vec<Operand>,
+    /// The size given for a memory operand (if any).
+    size: Option<usize>,
+}
+impl Stmt {
+    fn parse(line: usize, text: &str) -> Result<Self, String> {
+        let (mut lock, mut rep, mut repne) = (false, false, false);
+        let mut rest = text;
+        let name = loop {
+            let (word, tail) = rest.split_once(char::is_whitespace)
+                .unwrap_or((rest, ""));
+            rest = tail.trim();
+            match word {
+                "lock" => lock = true,
+                "rep" | "repe" | "repz" => rep = true,
+                "repne" | "repnz" => repne = true,
+                _ => break word,
+            }
+        };
+        let mnemonic = mnemonic(name)
+            .ok_or_else(|| format!("unknown mnemonic '{}'", name))?;
+
+        let mut ops = Vec::new();
+        let mut size = None;
+        if !rest.is_empty() {
+            for op in rest.split(',') {
+                let (op, op_size) = operand(op.trim())?;
+                if op_size.is_some() {
+                    size = op_size;
+                }
+                ops.push(op);
+            }
+        }
+        Ok(Self {
+            line, text: text.to_string(), mnemonic, lock, rep, repne, ops,
+            size
+        })
+    }
+
+    fn error(&self, reason: String) -> Error {
+        Error::InvalidAsm { line: self.line, text: self.text.clone(), reason }
+    }
+
+    /// Choose an encoding for this statement, given the address of the
+    /// target for branches.
+    fn select(&self, target: Option<u64>) -> Result<Instruction, String> {
+        let codes = Code::values().filter(|c| {
+            let op = c.op_code();
+            c.mnemonic() == self.mnemonic && op.mode64()
+                && op.op_count() as usize == self.ops.len()
+        });
+        if let Some(target) = target {
+            // Prefer near branches: the block encoder uses short branches
+            // wherever the target is close enough.
+            let kinds = [OpCodeOperandKind::br64_1, OpCodeOperandKind::br64_4];
+            let mut inst = codes
+                .filter(|c| kinds.contains(&c.op_code().op_kind(0)))
+                .filter_map(|c| Instruction::with_branch(c, target).ok())
+                .max_by_key(|i| {
+                    i.op_code().op_kind(0) == OpCodeOperandKind::br64_4
+                })
+                .ok_or_else(|| "not a relative branch".to_string())?;
+            self.set_prefixes(&mut inst);
+            return Ok(inst);
+        }
+        if self.ops.is_empty() {
+            if let Some(inst) = string(self.mnemonic) {
+                let mut inst = inst.map_err(|e| e.to_string())?;
+                self.set_prefixes(&mut inst);
+                return Ok(inst);
+            }
+        }
+        if self.ops.iter().any(|op| matches!(op, Operand::Label(_))) {
+            return Err("labels can only be used as branch targets"
+                .to_string());
+        }
+
+        let has_mem = self.ops.iter().any(|op| matches!(op, Operand::Mem(_)));
+        let mut best: Option<(Instruction, usize)> = None;
+        let mut sizes: Vec<usize> = Vec::new();
+        for code in codes {
+            let mut inst = match build(code, &self.ops) {
+                Some(inst) => inst,
+                None => continue,
+            };
+            self.set_prefixes(&mut inst);
+            let mem_size = inst.memory_size().size();
+            if self.size.is_some_and(|size| size != mem_size) {
+                continue;
+            }
+            let len = match Encoder::new(64).encode(&inst, 0) {
+                Ok(len) => len,
+                Err(_) => continue,
+            };
+            if !sizes.contains(&mem_size) {
+                sizes.push(mem_size);
+            }
+            if best.as_ref().is_none_or(|(_, best_len)| len < *best_len) {
+                best = Some((inst, len));
+            }
+        }
+        if has_mem && self.size.is_none() && sizes.len() > 1 {
+            return Err("ambiguous operand size (i.e. use 'qword ptr')"
+                .to_string());
+        }
+        best.map(|(inst, _)| inst)
+            .ok_or_else(|| "no encoding for these operands".to_string())
+    }
+
+    fn set_prefixes(&self, inst: &mut Instruction) {
+        inst.set_has_lock_prefix(self.lock);
+        inst.set_has_rep_prefix(self.rep);
+        inst.set_has_repne_prefix(self.repne);
+    }
+}
+
+/// Create an instruction with some operands (if the types of the operands
+/// are valid for some instruction).
+fn build(code: Code, ops: &[Operand]) -> Option<Instruction> {
+    use Operand::{ Reg as R, Mem as M, Imm as I, UImm as U, Imm64 as Q };
+    let res = match ops {
+        [] => Ok(Instruction::with(code)),
+        [R(a)] => Instruction::with1(code, *a),
+        [M(a)] => Instruction::with1(code, *a),
+        [I(a)] => Instruction::with1(code, *a),
+        [U(a)] => Instruction::with1(code, *a),
+
+        [M(a), R(b)] => Instruction::with2(code, *a, *b),
+        [M(a), I(b)] => Instruction::with2(code, *a, *b),
+        [M(a), U(b)] => Instruction::with2(code, *a, *b),
+        [R(a), M(b)] => Instruction::with2(code, *a, *b),
+        [R(a), R(b)] => Instruction::with2(code, *a, *b),
+        [R(a), I(b)] => Instruction::with2(code, *a, *b),
+        [R(a), U(b)] => Instruction::with2(code, *a, *b),
+        [R(a), Q(b)] => Instruction::with2(code, *a, *b),
+        [I(a), R(b)] => Instruction::with2(code, *a, *b),
+        [U(a), R(b)] => Instruction::with2(code, *a, *b),
+        [I(a), I(b)] => Instruction::with2(code, *a, *b),
+        [U(a), U(b)] => Instruction::with2(code, *a, *b),
+
+        [M(a), R(b), R(c)] => Instruction::with3(code, *a, *b, *c),
+        [M(a), R(b), I(c)] => Instruction::with3(code, *a, *b, *c),
+        [M(a), R(b), U(c)] => Instruction::with3(code, *a, *b, *c),
+        [R(a), M(b), R(c)] => Instruction::with3(code, *a, *b, *c),
+        [R(a), M(b), I(c)] => Instruction::with3(code, *a, *b, *c),
+        [R(a), M(b), U(c)] => Instruction::with3(code, *a, *b, *c),
+        [R(a), R(b), M(c)] => Instruction::with3(code, *a, *b, *c),
+        [R(a), R(b), R(c)] => Instruction::with3(code, *a, *b, *c),
+        [R(a), R(b), I(c)] => Instruction::with3(code, *a, *b, *c),
+        [R(a), R(b), U(c)] => Instruction::with3(code, *a, *b, *c),
+        [R(a), I(b), I(c)] => Instruction::with3(code, *a, *b, *c),
+        [R(a), U(b), U(c)] => Instruction::with3(code, *a, *b, *c),
+
+        [R(a), R(b), M(c), R(d)] => Instruction::with4(code, *a, *b, *c, *d),
+        [R(a), R(b), M(c), I(d)] => Instruction::with4(code, *a, *b, *c, *d),
+        [R(a), R(b), M(c), U(d)] => Instruction::with4(code, *a, *b, *c, *d),
+        [R(a), R(b), R(c), M(d)] => Instruction::with4(code, *a, *b, *c, *d),
+        [R(a), R(b), R(c), R(d)] => Instruction::with4(code, *a, *b, *c, *d),
+        [R(a), R(b), R(c), I(d)] => Instruction::with4(code, *a, *b, *c, *d),
+        [R(a), R(b), R(c), U(d)] => Instruction::with4(code, *a, *b, *c, *d),
+        [R(a), R(b), I(c), I(d)] => Instruction::with4(code, *a, *b, *c, *d),
+        [R(a), R(b), U(c), U(d)] => Instruction::with4(code, *a, *b, *c, *d),
+
+        [R(a), R(b), M(c), R(d), I(e)] => {
+            Instruction::with5(code, *a, *b, *c, *d, *e)
+        },
+        [R(a), R(b), M(c), R(d), U(e)] => {
+            Instruction::with5(code, *a, *b, *c, *d, *e)
+        },
+        [R(a), R(b), R(c), M(d), I(e)] => {
+            Instruction::with5(code, *a, *b, *c, *d, *e)
+        },
+        [R(a), R(b), R(c), M(d), U(e)] => {
+            Instruction::with5(code, *a, *b, *c, *d, *e)
+        },
+        [R(a), R(b), R(c), R(d), I(e)] => {
+            Instruction::with5(code, *a, *b, *c, *d, *e)
+        },
+        [R(a), R(b), R(c), R(d), U(e)] => {
+            Instruction::with5(code, *a, *b, *c, *d, *e)
+        },
+        _ => return None,
+    };
+    res.ok()
+}
+
+/// Create a string instruction (with implicit operands in RSI and RDI).
+fn string(mnemonic: Mnemonic) -> Option<Result<Instruction, IcedError>> {
+    use Mnemonic::*;
+    let (seg, rep) = (Register::None, RepPrefixKind::None);
+    Some(match mnemonic {
+        Movsb => Instruction::with_movsb(64, seg, rep),
+        Movsw => Instruction::with_movsw(64, seg, rep),
+        Movsd => Instruction::with_movsd(64, seg, rep),
+        Movsq => Instruction::with_movsq(64, seg, rep),
+        Cmpsb => Instruction::with_cmpsb(64, seg, rep),
+        Cmpsw => Instruction::with_cmpsw(64, seg, rep),
+        Cmpsd => Instruction::with_cmpsd(64, seg, rep),
+        Cmpsq => Instruction::with_cmpsq(64, seg, rep),
+        Lodsb => Instruction::with_lodsb(64, seg, rep),
+        Lodsw => Instruction::with_lodsw(64, seg, rep),
+        Lodsd => Instruction::with_lodsd(64, seg, rep),
+        Lodsq => Instruction::with_lodsq(64, seg, rep),
+        Stosb => Instruction::with_stosb(64, rep),
+        Stosw => Instruction::with_stosw(64, rep),
+        Stosd => Instruction::with_stosd(64, rep),
+        Stosq => Instruction::with_stosq(64, rep),
+        Scasb => Instruction::with_scasb(64, rep),
+        Scasw => Instruction::with_scasw(64, rep),
+        Scasd => Instruction::with_scasd(64, rep),
+        Scasq => Instruction::with_scasq(64, rep),
+        _ => return None,
+    })
+}
+
+/// Split a label definition (i.e. `name:`) from the start of a statement.
+fn split_label(s: &str) -> Option<(&str, &str)> {
+    let (name, rest) = s.split_once(':')?;
+    if is_ident(name) && register(name).is_none() {
+        Some((name, rest))
+    } else {
+        None
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_'
+        || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Parse an operand, along with the size given for a memory operand.
+fn operand(s: &str) -> Result<(Operand, Option<usize>), String> {
+    let (word, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+    if let Some((_, size)) = SIZES.iter().find(|(name, _)| *name == word) {
+        let rest = rest.trim_start();
+        let rest = rest.strip_prefix("ptr").unwrap_or(rest).trim_start();
+        if !rest.contains('[') {
+            return Err(format!("expected a memory operand after '{}'",
+                word));
+        }
+        return Ok((Operand::Mem(memory(rest)?), Some(*size)));
+    }
+    if s.contains('[') {
+        return Ok((Operand::Mem(memory(s)?), None));
+    }
+    if let Some(reg) = register(s) {
+        return Ok((Operand::Reg(reg), None));
+    }
+    if let Some(val) = number(s) {
+        let op = if let Ok(val) = i32::try_from(val) {
+            Operand::Imm(val)
+        } else if let Ok(val) = u32::try_from(val) {
+            Operand::UImm(val)
+        } else {
+            Operand::Imm64(val)
+        };
+        return Ok((op, None));
+    }
+    if is_ident(s) {
+        return Ok((Operand::Label(s.to_string()), None));
+    }
+    Err(format!("invalid operand '{}'", s))
+}
+
+/// Parse a memory operand (i.e. `fs:[rax + rbx*8 - 0x10]`).
+fn memory(s: &str) -> Result<MemoryOperand, String> {
+    let err = || format!("invalid memory operand '{}'", s);
+    let (seg, rest) = s.split_once('[').ok_or_else(err)?;
+    let inner = rest.trim_end().strip_suffix(']').ok_or_else(err)?;
+    let seg = match seg.trim().strip_suffix(':') {
+        Some(seg) => register(seg.trim()).ok_or_else(err)?,
+        None if seg.trim().is_empty() => Register::None,
+        None => return Err(err()),
+    };
+
+    let mut mem = MemoryOperand {
+        segment_prefix: seg, scale: 1, ..Default::default()
+    };
+    // Split into terms, keeping the sign of each
+    let mut terms = Vec::new();
+    let mut start = 0;
+    for (idx, c) in inner.char_indices() {
+        if idx > 0 && (c == '+' || c == '-') {
+            terms.push(&inner[start..idx]);
+            start = idx;
+        }
+    }
+    terms.push(&inner[start..]);
+
+    for term in terms {
+        let term = term.trim();
+        let (neg, term) = match term.strip_prefix('-') {
+            Some(t) => (true, t.trim()),
+            None => (false, term.strip_prefix('+').unwrap_or(term).trim()),
+        };
+        if let Some((a, b)) = term.split_once('*') {
+            let (a, b) = (a.trim(), b.trim());
+            let (reg, scale) = match (register(a), register(b)) {
+                (Some(reg), None) => (reg, number(b)),
+                (None, Some(reg)) => (reg, number(a)),
+                _ => return Err(err()),
+            };
+            let scale = scale.filter(|s| [1, 2, 4, 8].contains(s))
+                .ok_or_else(|| format!("invalid scale in '{}'", s))?;
+            if neg || mem.index != Register::None {
+                return Err(err());
+            }
+            mem.index = reg;
+            mem.scale = scale as u32;
+        } else if let Some(reg) = register(term) {
+            if reg == Register::RIP {
+                return Err("RIP-relative operands aren't supported"
+                    .to_string());
+            }
+            if neg {
+                return Err(err());
+            }
+            if mem.base == Register::None {
+                mem.base = reg;
+            } else if mem.index == Register::None {
+                mem.index = reg;
+            } else {
+                return Err(err());
+            }
+        } else if let Some(val) = number(term) {
+            mem.displacement += if neg { -val } else { val };
+            mem.displ_size = 1;
+        } else {
+            return Err(err());
+        }
+    }
+    Ok(mem)
+}
+
+/// Parse a register name.
+fn register(s: &str) -> Option<Register> {
+    // iced-x86 uses i.e. `R8L` for the low byte of R8
+    let alias = s.strip_suffix('b')
+        .filter(|r| r.starts_with('r') && r[1..].parse::<u8>().is_ok())
+        .map(|r| format!("{}l", r));
+    let name = alias.as_deref().unwrap_or(s);
+    Register::values().find(|r| {
+        *r != Register::None && format!("{:?}", r).eq_ignore_ascii_case(name)
+    })
+}
+
+/// Parse a mnemonic (accepting aliases for condition codes).
+fn mnemonic(s: &str) -> Option<Mnemonic> {
+    let alias = ["j", "cmov", "set"].iter().find_map(|prefix| {
+        let cc = s.strip_prefix(prefix)?;
+        let (_, to) = CONDITIONS.iter().find(|(from, _)| *from == cc)?;
+        Some(format!("{}{}", prefix, to))
+    });
+    let name = alias.as_deref().unwrap_or(s);
+    Mnemonic::values().find(|m| {
+        *m != Mnemonic::INVALID && format!("{:?}", m).eq_ignore_ascii_case(name)
+    })
+}
+
+/// Parse a decimal or hexadecimal number (i.e. `-16`, `0x10`, or `10h`).
+fn number(s: &str) -> Option<i64> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s.trim_start()),
+        None => (false, s),
+    };
+    let s = s.replace('_', "");
+    let val = if let Some(hex) = s.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()?
+    } else if let Some(hex) = s.strip_suffix('h') {
+        if !hex.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        u64::from_str_radix(hex, 16).ok()?
+    } else {
+        s.parse::<u64>().ok()?
+    } as i64;
+    Some(if neg { val.wrapping_neg() } else { val })
+}
@@ -0,0 +1,465 @@
+//! Builder interface for generating tests at runtime.
+//!
+//! [TestBuilder] wraps an [Assembler] and emits the different parts of a
+//! test (the prologue, measurements, the body, loops, and the epilogue) with
+//! separate methods. Unlike the macros in [crate::codegen], this lets you
+//! compose tests from smaller pieces, parameterize them at runtime, and pass
+//! them between functions.
+//!
+//! The result is a [CompiledTest], which knows its own calling convention
+//! and which counters are read by the emitted code.
+//!
+//! ```no_run
+//! use lamina::*;
+//! use lamina::builder::TestBuilder;
+//!
+//! fn main() -> Result<(), lamina::Error> {
+//!     let num_nops = 4;
+//!     let test = TestBuilder::pmc()?
+//!         .prologue()
+//!         .start()
+//!         .repeat(num_nops, |asm| { dynasm!(asm ; nop); })
+//!         .stop()
+//!         .epilogue()
+//!         .finish()?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! ## Register use
+//!
+//! - R15 holds the pointer to the set of results when reading PMCs by
+//!   reference (see [crate::emit_rdpmc_test_all])
+//! - R9-R14 accumulate the value of each PMC when reading by reference
+//! - R14 accumulates the value when returning a single value in RAX
+//! - RAX, RCX, and RDX are clobbered by each measurement
+//!
+//! Each measurement reads the full width of a counter (see
+//! [crate::pmc::COUNTER_WIDTH]). Since the difference is computed in 64-bit
+//! registers, a counter which wraps around during a test leaves the upper
+//! bits set; these are masked off afterwards, and the value of a merged pair
+//! of counters is computed from both counters (see [crate::PMCResults]).
+//!
+
+use dynasmrt::{ dynasm, DynasmApi, DynasmLabelApi };
+use dynasmrt::{ Assembler, AssemblyOffset, ExecutableBuffer };
+use dynasmrt::x64::{ X64Relocation, Rq };
+
+use crate::codegen::{ self, ReadStub };
+use crate::error::Error;
+use crate::PMCTestInterface;
+
+/// The type of assembler wrapped by [TestBuilder].
+pub type Asm = Assembler<X64Relocation>;
+
+/// Indicates the strategy used to pass result values out of emitted code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Convention {
+    /// Emitted code takes a pointer to an array of six values.
+    ByRef,
+    /// Emitted code returns a single value in RAX.
+    ByVal,
+}
+
+/// The source of the value(s) measured by a test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// Some set of PMCs (indexed by the `PERF_CTL` slot) read with RDPMC.
+    /// The index for each counter is passed in the array of results.
+    Pmc([bool; 6]),
+    /// A single PMC read with RDPMC, using a fixed index chosen when the
+    /// code is emitted. `ctr` is the `PERF_CTL` slot, and `sel` is the
+    /// index passed to RDPMC (in ECX).
+    SinglePmc { ctr: usize, sel: u32 },
+    /// The APERF register, read with RDPRU.
+    Aperf,
+}
+impl Source {
+    /// Return the calling convention used to obtain values from this source.
+    pub fn convention(&self) -> Convention {
+        match self {
+            Source::Pmc(_) => Convention::ByRef,
+            Source::SinglePmc { .. } | Source::Aperf => Convention::ByVal,
+        }
+    }
+
+    /// Return the set of `PERF_CTL` slots read with RDPMC.
+    pub fn counters(&self) -> [bool; 6] {
+        match self {
+            Source::Pmc(ctrs) => *ctrs,
+            Source::SinglePmc { ctr, .. } => {
+                let mut res = [false; 6];
+                res[*ctr] = true;
+                res
+            },
+            Source::Aperf => [false; 6],
+        }
+    }
+}
+
+/// Emitted code, along with its calling convention and the set of counters
+/// it reads.
+pub struct CompiledTest {
+    /// Buffer containing emitted code.
+    pub buf: ExecutableBuffer,
+    /// The source of measured value(s).
+    pub source: Source,
+    /// Stub called in place of RDPMC (if any).
+    pub stub: Option<ReadStub>,
+}
+impl CompiledTest {
+    /// Return a pointer to emitted code.
+    pub fn ptr(&self) -> *const u8 { self.buf.ptr(AssemblyOffset(0)) }
+
+    /// Return the size of emitted code in bytes.
+    pub fn len(&self) -> usize { self.buf.len() }
+
+    /// Returns true if no code was emitted.
+    pub fn is_empty(&self) -> bool { self.buf.len() == 0 }
+
+    /// Return the calling convention for emitted code.
+    pub fn convention(&self) -> Convention { self.source.convention() }
+
+    /// Return the set of `PERF_CTL` slots read by emitted code.
+    pub fn counters(&self) -> [bool; 6] { self.source.counters() }
+
+    /// Return a typed function pointer to emitted code.
+    pub fn interface(&self) -> PMCTestInterface {
+        unsafe {
+            match self.convention() {
+                Convention::ByRef => {
+                    PMCTestInterface::ByRef(std::mem::transmute::<
+                        *const u8, crate::PMCTestFn
+                    >(self.ptr()))
+                },
+                Convention::ByVal => {
+                    PMCTestInterface::ByVal(std::mem::transmute::<
+                        *const u8, crate::SimpleTestFn
+                    >(self.ptr()))
+                },
+            }
+        }
+    }
+}
+
+/// Generates a test piece-by-piece.
+///
+/// The methods here are expected to be called in order: [Self::prologue],
+/// [Self::start], any number of calls which emit the body of the test,
+/// [Self::stop], and finally [Self::epilogue].
+pub struct TestBuilder {
+    /// The underlying assembler.
+    asm: Asm,
+    /// The source of measured value(s).
+    source: Source,
+    /// Optional stub called in place of RDPMC.
+    stub: Option<ReadStub>,
+}
+
+impl TestBuilder {
+    /// Registers used to accumulate the value of each PMC when reading by
+    /// reference.
+    const ACC_REGS: [Rq; 6] = [
+        Rq::R9, Rq::R10, Rq::R11, Rq::R12, Rq::R13, Rq::R14
+    ];
+
+    /// Create a new builder for a test measuring some [Source].
+    pub fn new(source: Source) -> Result<Self, Error> {
+        if let Source::SinglePmc { ctr, .. } = source {
+            assert!(ctr < 6);
+        }
+        Ok(Self { asm: codegen::new_assembler()?, source, stub: None })
+    }
+
+    /// Create a new builder for a test reading all six PMCs.
+    pub fn pmc() -> Result<Self, Error> {
+        Self::new(Source::Pmc([true; 6]))
+    }
+
+    /// Create a new builder for a test reading a single PMC.
+    pub fn single(ctr: usize, sel: u32) -> Result<Self, Error> {
+        Self::new(Source::SinglePmc { ctr, sel })
+    }
+
+    /// Create a new builder for a test reading APERF.
+    pub fn aperf() -> Result<Self, Error> {
+        Self::new(Source::Aperf)
+    }
+
+    /// Call some [ReadStub] in place of each RDPMC.
+    pub fn read_stub(mut self, stub: Option<ReadStub>) -> Self {
+        self.stub = stub;
+        self
+    }
+
+    /// Create a test with an empty body, for measuring the overhead
+    /// associated with some [Source].
+    pub fn empty(source: Source, stub: Option<ReadStub>)
+        -> Result<CompiledTest, Error>
+    {
+        Self::new(source)?
+            .read_stub(stub)
+            .prologue()
+            .start()
+            .stop()
+            .epilogue()
+            .finish()
+    }
+
+    /// Return a reference to the underlying assembler.
+    pub fn asm(&mut self) -> &mut Asm { &mut self.asm }
+
+    /// Emit the common prologue.
+    ///
+    /// Pushes the SysV ABI callee-save registers onto the stack, and clears
+    /// all of the general-purpose registers (with the exception of RSP, and
+    /// R15 when reading PMCs by reference).
+    pub fn prologue(mut self) -> Self {
+        dynasm!(self.asm
+            ; .arch     x64
+            ; push      rbp
+            ; push      rbx
+            ; push      rdi
+            ; push      rsi
+            ; push      r12
+            ; push      r13
+            ; push      r14
+            ; push      r15
+            ; mfence
+            ; lfence
+        );
+        if self.source.convention() == Convention::ByRef {
+            dynasm!(self.asm ; mov r15, rdi);
+        } else {
+            dynasm!(self.asm ; xor r15, r15);
+        }
+        dynasm!(self.asm
+            ; xor       rax, rax
+            ; xor       rbx, rbx
+            ; xor       rcx, rcx
+            ; xor       rdx, rdx
+            ; xor       rsi, rsi
+            ; xor       rdi, rdi
+            ; xor       rbp, rbp
+            ; xor        r8, r8
+            ; xor        r9, r9
+            ; xor       r10, r10
+            ; xor       r11, r11
+            ; xor       r12, r12
+            ; xor       r13, r13
+            ; xor       r14, r14
+            ; mfence
+            ; lfence
+        );
+        self
+    }
+
+    /// Emit a single RDPMC, leaving the value in RAX.
+    /// Assumes that ECX already holds the index of the counter.
+    fn emit_rdpmc(&mut self) {
+        match self.stub {
+            // RDPMC returns the high bits of the counter in EDX
+            None => dynasm!(self.asm 
+                ; lfence 
+                ; rdpmc 
+                ; lfence
+                ; shl rdx, 32
+                ; or rax, rdx
+            ),
+            Some(s) => dynasm!(self.asm
+                // Keep the stack 16-byte aligned for the call
+                ; push r9 ; push r10 ; push r11
+                ; mov rsi, rcx
+                ; mov rdi, QWORD s.arg as _
+                ; mov rax, QWORD s.func as usize as _
+                ; call rax
+                ; pop r11 ; pop r10 ; pop r9
+            ),
+        }
+    }
+
+    /// Emit a single measurement of some PMC read by reference, adding or
+    /// subtracting the result from the associated register (R9-R14).
+    ///
+    /// The index of the counter is taken from the array of results. If the
+    /// index has the sign bit set, the measurement is skipped.
+    fn emit_pmc_by_ref(&mut self, idx: usize, add: bool) {
+        let off = (idx * 8) as i32;
+        let reg = Self::ACC_REGS[idx];
+        dynasm!(self.asm ; mov rcx, [r15 + off] ; test rcx, rcx ; js >skip);
+        self.emit_rdpmc();
+        if add {
+            dynasm!(self.asm ; add Rq(reg), rax ; skip:);
+        } else {
+            dynasm!(self.asm ; sub Rq(reg), rax ; skip:);
+        }
+    }
+
+    /// Emit a single measurement of some PMC with a fixed index, adding or
+    /// subtracting the result from R14.
+    fn emit_pmc_by_val(&mut self, sel: u32, add: bool) {
+        dynasm!(self.asm ; mov ecx, sel as _);
+        self.emit_rdpmc();
+        if add {
+            dynasm!(self.asm ; add r14, rax);
+        } else {
+            dynasm!(self.asm ; sub r14, rax);
+        }
+    }
+
+    /// Emit a single measurement of APERF, adding or subtracting the result
+    /// from R14.
+    fn emit_aperf(&mut self, add: bool) {
+        dynasm!(self.asm
+            ; mov       rcx, 1
+            ; lfence
+            ; .bytes    crate::x86::RDPRU.iter()
+            ; lfence
+            ; shl       rdx, 32
+            ; or        rdx, rax
+        );
+        if add {
+            dynasm!(self.asm ; add r14, rdx);
+        } else {
+            dynasm!(self.asm ; sub r14, rdx);
+        }
+    }
+
+    /// Emit the first measurement.
+    pub fn start(mut self) -> Self {
+        match self.source {
+            Source::Pmc(ctrs) => {
+                for idx in (0..6).rev().filter(|idx| ctrs[*idx]) {
+                    self.emit_pmc_by_ref(idx, false);
+                }
+            },
+            Source::SinglePmc { sel, .. } => self.emit_pmc_by_val(sel, false),
+            Source::Aperf => self.emit_aperf(false),
+        }
+        self
+    }
+
+    /// Emit the second measurement (computing the difference).
+    pub fn stop(mut self) -> Self {
+        match self.source {
+            Source::Pmc(ctrs) => {
+                for idx in (0..6).filter(|idx| ctrs[*idx]) {
+                    self.emit_pmc_by_ref(idx, true);
+                }
+            },
+            Source::SinglePmc { sel, .. } => self.emit_pmc_by_val(sel, true),
+            Source::Aperf => self.emit_aperf(true),
+        }
+        self
+    }
+
+    /// Emit some code with a closure.
+    pub fn body(mut self, f: impl FnOnce(&mut Asm)) -> Self {
+        f(&mut self.asm);
+        self
+    }
+
+    /// Emit some code with a closure, some number of times (unrolled).
+    pub fn repeat(mut self, n: usize, mut f: impl FnMut(&mut Asm)) -> Self {
+        for _ in 0..n {
+            f(&mut self.asm);
+        }
+        self
+    }
+
+    /// Emit a loop with some body, using some register as the loop counter.
+    /// The body must not clobber the register.
+    pub fn emit_loop(mut self, reg: Rq, iters: usize,
+        f: impl FnOnce(&mut Asm)) -> Self
+    {
+        let head = self.asm.new_dynamic_label();
+        dynasm!(self.asm
+            ; mov       Rq(reg), QWORD iters as _
+            ; .align    64
+            ; =>head
+            ; lfence
+        );
+        f(&mut self.asm);
+        dynasm!(self.asm
+            ; sub       Rq(reg), 1
+            ; jne       =>head
+            ; lfence
+        );
+        self
+    }
+
+    /// Emit the common epilogue.
+    ///
+    /// Moves the result(s) into place, pops the SysV ABI callee-save
+    /// registers from the stack, and returns.
+    pub fn epilogue(mut self) -> Self {
+        match self.source.convention() {
+            Convention::ByRef => dynasm!(self.asm
+                ; mov [r15 + 0x00], r9
+                ; mov [r15 + 0x08], r10
+                ; mov [r15 + 0x10], r11
+                ; mov [r15 + 0x18], r12
+                ; mov [r15 + 0x20], r13
+                ; mov [r15 + 0x28], r14
+            ),
+            Convention::ByVal => dynasm!(self.asm ; mov rax, r14),
+        }
+        dynasm!(self.asm
+            ; mfence
+            ; pop       r15
+            ; pop       r14
+            ; pop       r13
+            ; pop       r12
+            ; pop       rsi
+            ; pop       rdi
+            ; pop       rbx
+            ; pop       rbp
+            ; ret
+            ; lfence
+        );
+        self
+    }
+
+    /// Resolve all labels and return the resulting [CompiledTest].
+    pub fn finish(self) -> Result<CompiledTest, Error> {
+        Ok(CompiledTest {
+            buf: codegen::finalize(self.asm)?,
+            source: self.source,
+            stub: self.stub,
+        })
+    }
+}
+
+/// Emit a variation on Henry Wong's gadget for measuring reorder buffer
+/// capacity (see [crate::emit_hwong_gadget_test]).
+///
+/// `body_a` and `body_b` are each emitted `inner_unroll` times after the
+/// first and second high-latency loads, respectively.
+#[allow(clippy::too_many_arguments)]
+pub fn hwong_gadget(tgt_ptr1: *const usize, tgt_ptr2: *const usize,
+    free_ptr: *const usize, loop_iters: usize,
+    outer_unroll: usize, inner_unroll: usize,
+    mut body_a: impl FnMut(&mut Asm), mut body_b: impl FnMut(&mut Asm),
+) -> Result<CompiledTest, Error>
+{
+    TestBuilder::aperf()?
+        .prologue()
+        .body(|asm| dynasm!(asm
+            ; mov       rdi, QWORD tgt_ptr1 as _
+            ; mov       rsi, QWORD tgt_ptr2 as _
+            ; mov       r15, QWORD free_ptr as _
+        ))
+        .start()
+        .emit_loop(Rq::R13, loop_iters, |asm| {
+            for _ in 0..outer_unroll {
+                dynasm!(asm ; mov rdi, [rdi]);
+                for _ in 0..inner_unroll { body_a(asm); }
+                dynasm!(asm ; mov rsi, [rsi]);
+                for _ in 0..inner_unroll { body_b(asm); }
+            }
+        })
+        .stop()
+        .epilogue()
+        .finish()
+}
+
@@ -0,0 +1,307 @@
+//! Measuring the capacity of out-of-order structures with a sweep over
+//! variations on Henry Wong's gadget (see [crate::builder::hwong_gadget]).
+//!
+//! A [Sweep] emits the gadget with an increasing number of padding
+//! instructions between the two high-latency loads, and measures the number
+//! of cycles taken by each gadget. While both loads fit in the structure
+//! being measured, their latencies overlap; once the padding instructions
+//! fill the structure, the loads are serialized and the number of cycles
+//! approximately doubles. [SweepResults::knee] locates this step change.
+//!
+//! ```no_run
+//! use lamina::*;
+//! use lamina::capacity::Sweep;
+//!
+//! fn main() -> Result<(), lamina::Error> {
+//!     lamina::util::pin_to_core(0)?;
+//!     let res = Sweep::new("rob")
+//!         .pads(0..=256)
+//!         .run(
+//!             |asm| { dynasm!(asm ; nop); },
+//!             |asm| { dynasm!(asm ; nop); },
+//!         )?;
+//!     res.report();
+//!
+//!     let size = res.knee().filter(|k| k.is_confident()).map(|k| k.size);
+//!     println!("{:?}", size);
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The estimated size is the number of padding instructions (between each
+//! pair of loads) where the step occurs, and doesn't account for the loads
+//! themselves.
+//!
+
+use std::ops::RangeInclusive;
+
+use crate::builder::{ self, Asm };
+use crate::chase::PointerMaze;
+use crate::stats::{ Samples, Summary };
+use crate::util::Xorshift64;
+use crate::error::Error;
+
+type Err<T> = Result<T, Error>;
+
+/// The number of pointers in the chain used for high-latency loads.
+const MAZE_SIZE: usize = 0x1000_0000;
+
+/// The distance between successive pointers in the chain (a page, with
+/// 8-byte pointers).
+const MAZE_STRIDE: usize = 512;
+
+/// Configuration for a sweep over the number of padding instructions.
+#[derive(Clone, Debug)]
+pub struct Sweep {
+    /// The name of the structure being measured.
+    pub name: &'static str,
+    /// The number of padding instructions after each load.
+    pub pads: RangeInclusive<usize>,
+    /// The number of times the gadget is unrolled within the loop.
+    pub unroll: usize,
+    /// The number of loop iterations.
+    pub iters: usize,
+    /// The number of measurements taken for each number of padding
+    /// instructions.
+    pub samples: usize,
+    /// Pointer passed to the padding instructions in R15 (if any).
+    pub free_ptr: *const usize,
+}
+impl Sweep {
+    /// Create a new sweep with the default configuration.
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            pads: 0..=256,
+            unroll: 32,
+            iters: 0x80,
+            samples: 512,
+            free_ptr: std::ptr::null(),
+        }
+    }
+
+    pub fn pads(mut self, pads: RangeInclusive<usize>) -> Self {
+        self.pads = pads;
+        self
+    }
+
+    pub fn unroll(mut self, unroll: usize) -> Self {
+        self.unroll = unroll;
+        self
+    }
+
+    pub fn iters(mut self, iters: usize) -> Self {
+        self.iters = iters;
+        self
+    }
+
+    pub fn samples(mut self, samples: usize) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn free_ptr(mut self, ptr: *const usize) -> Self {
+        self.free_ptr = ptr;
+        self
+    }
+
+    /// Run the sweep, where `body_a` and `body_b` each emit a single
+    /// padding instruction (after the first and second load, respectively).
+    ///
+    /// See [crate::emit_hwong_gadget_test] for the registers available to
+    /// padding instructions.
+    pub fn run(&self, mut body_a: impl FnMut(&mut Asm),
+        mut body_b: impl FnMut(&mut Asm)) -> Err<SweepResults>
+    {
+        // Create a random cyclic chain of pointers, for loads that reliably
+        // miss in the cache
+        let mut rng = Xorshift64::new();
+        let mut mem = PointerMaze::<MAZE_SIZE>::new();
+        mem.shuffle(&mut rng, MAZE_STRIDE);
+        mem.flush();
+        let ptr_a = mem.head_ptr() as *const usize;
+        let ptr_b = mem.mid_ptr() as *const usize;
+
+        let mut points = Vec::new();
+        for num_pad in self.pads.clone() {
+            mem.flush();
+            let test = builder::hwong_gadget(ptr_a, ptr_b, self.free_ptr,
+                self.iters, self.unroll, num_pad, &mut body_a, &mut body_b
+            )?;
+            let res: Vec<usize> = (0..self.samples)
+                .map(|_| crate::run_simple_test(&test.buf))
+                .collect();
+
+            // Normalize to the number of gadgets in each test
+            let summary = res.summary()
+                .scaled((self.iters * self.unroll) as f64);
+            points.push(SweepPoint { pad: num_pad, summary });
+        }
+        Ok(SweepResults { name: self.name, points })
+    }
+}
+
+/// Cycles per gadget for some number of padding instructions.
+#[derive(Clone, Debug)]
+pub struct SweepPoint {
+    pub pad: usize,
+    pub summary: Summary,
+}
+
+/// Results from a [Sweep].
+#[derive(Clone, Debug)]
+pub struct SweepResults {
+    /// The name of the structure being measured.
+    pub name: &'static str,
+    /// Results for each number of padding instructions (in order).
+    pub points: Vec<SweepPoint>,
+}
+impl SweepResults {
+    /// Print results for each number of padding instructions (in the format
+    /// expected by `scripts/plot.py`).
+    pub fn print(&self) {
+        for p in self.points.iter() {
+            let s = &p.summary;
+            println!("{:03}: min={:.3} avg={:.3} max={:.3} med={:.3} sd={:.3}",
+                p.pad, s.min, s.mean, s.max, s.median, s.stddev);
+        }
+    }
+
+    /// Print results (see [SweepResults::print]), followed by the estimated
+    /// size on stderr (so that the output can still be plotted).
+    pub fn report(&self) {
+        self.print();
+        match self.knee() {
+            Some(knee) => eprintln!("[*] {}: {}", self.name, knee),
+            None => eprintln!("[!] {}: not enough points", self.name),
+        }
+    }
+
+    /// Locate the step change in the median number of cycles (if there are
+    /// at least two points).
+    ///
+    /// The medians are fit with two constant levels, choosing the point
+    /// where they split which minimizes the squared error.
+    pub fn knee(&self) -> Option<Knee> {
+        let pads: Vec<usize> = self.points.iter().map(|p| p.pad).collect();
+        let meds: Vec<f64> = self.points.iter()
+            .map(|p| p.summary.median)
+            .collect();
+        Knee::fit(&pads, &meds)
+    }
+}
+
+/// A step change in the number of cycles taken by each gadget (see
+/// [SweepResults::knee]).
+#[derive(Clone, Copy, Debug)]
+pub struct Knee {
+    /// The estimated size of the structure: the first number of padding
+    /// instructions where the step begins.
+    pub size: usize,
+    /// The range of padding instructions over which the step occurs (from
+    /// 10% to 90% of the step height).
+    pub range: (usize, usize),
+    /// Mean cycles per gadget before the step.
+    pub low: f64,
+    /// Mean cycles per gadget after the step.
+    pub high: f64,
+    /// The step height relative to the residual standard deviation.
+    pub snr: f64,
+}
+impl Knee {
+    /// The minimum ratio between levels for a confident estimate.
+    pub const MIN_RATIO: f64 = 1.5;
+    /// The minimum signal-to-noise ratio for a confident estimate.
+    pub const MIN_SNR: f64 = 5.0;
+
+    /// Fit two constant levels to some values (one for each number of
+    /// padding instructions).
+    ///
+    /// ```
+    /// use lamina::capacity::Knee;
+    /// let pads: Vec<usize> = (0..8).collect();
+    /// let vals = [10.0, 10.2, 9.9, 10.1, 15.0, 20.1, 19.8, 20.0];
+    /// let knee = Knee::fit(&pads, &vals).unwrap();
+    /// assert_eq!(knee.size, 4);
+    /// assert_eq!(knee.range, (4, 5));
+    /// assert!(knee.is_confident());
+    ///
+    /// // An outlier (i.e. a cache miss on the first sample) is ignored
+    /// let vals = [14.0, 10.2, 9.9, 10.1, 15.0, 20.1, 19.8, 20.0];
+    /// let knee = Knee::fit(&pads, &vals).unwrap();
+    /// assert_eq!(knee.range, (4, 5));
+    /// ```
+    pub fn fit(pads: &[usize], vals: &[f64]) -> Option<Self> {
+        assert_eq!(pads.len(), vals.len());
+        let n = vals.len();
+        if n < 2 {
+            return None;
+        }
+        // Prefix sums of values and squares, for the error of each segment
+        let mut sum = vec![0.0; n + 1];
+        let mut sq = vec![0.0; n + 1];
+        for (i, v) in vals.iter().enumerate() {
+            sum[i + 1] = sum[i] + v;
+            sq[i + 1] = sq[i] + v * v;
+        }
+        let sse = |a: usize, b: usize| {
+            if a == b {
+                return 0.0;
+            }
+            let (s, len) = (sum[b] - sum[a], (b - a) as f64);
+            (sq[b] - sq[a]) - s * s / len
+        };
+        let mean = |a: usize, b: usize| (sum[b] - sum[a]) / (b - a) as f64;
+        let split = (1..n)
+            .min_by(|a, b| {
+                let err = |k: usize| sse(0, k) + sse(k, n);
+                err(*a).total_cmp(&err(*b))
+            })?;
+
+        // The step starts at or before the split, and ends at or after it.
+        // Points in between are left out of each level.
+        let (low, step) = (mean(0, split), mean(split, n) - mean(0, split));
+        let above = |i: &usize, frac: f64| vals[*i] - low > frac * step;
+        // Walk backward from the split, so that an early outlier doesn't
+        // pull the start of the step to the beginning
+        let start = (0..split).rev().take_while(|i| above(i, 0.1)).last()
+            .unwrap_or(split);
+        let end = (split..n).find(|i| above(i, 0.9)).unwrap_or(split);
+
+        let low = if start > 0 { mean(0, start) } else { low };
+        let high = mean(end, n);
+        let dof = (start + n - end).saturating_sub(2);
+        let noise = if dof > 0 {
+            ((sse(0, start) + sse(end, n)).max(0.0) / dof as f64).sqrt()
+        } else {
+            0.0
+        };
+        let snr = if noise > 0.0 {
+            (high - low) / noise
+        } else {
+            f64::INFINITY
+        };
+        Some(Self {
+            size: pads[start],
+            range: (pads[start], pads[end]),
+            low, high, snr
+        })
+    }
+
+    /// The ratio of cycles after the step to cycles before the step.
+    pub fn ratio(&self) -> f64 { self.high / self.low }
+
+    /// Returns true if the step is large relative to the noise, and the
+    /// number of cycles at least increases by [Knee::MIN_RATIO].
+    pub fn is_confident(&self) -> bool {
+        self.ratio() >= Self::MIN_RATIO && self.snr >= Self::MIN_SNR
+    }
+}
+impl std::fmt::Display for Knee {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "estimated size: {} (step over {}..={}, {:.3} -> {:.3} \
+            cycles, {:.2}x, snr={:.1}{})", self.size, self.range.0,
+            self.range.1, self.low, self.high, self.ratio(), self.snr,
+            if self.is_confident() { "" } else { ", low confidence" })
+    }
+}
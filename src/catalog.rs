@@ -0,0 +1,366 @@
+//! Per-microarchitecture tables of PMC events.
+//!
+//! [crate::event::Event] only describes events on Zen 2 machines. A [Catalog]
+//! is a table of the events (and the unit masks for each event) defined for
+//! a particular [Uarch]. The catalog for the running CPU is selected with
+//! `CPUID` by [Catalog::native].
+//!
+//! Events and unit masks are named after the Linux `perf` event names
+//! (i.e. `ex_ret_instr` or `ls_dispatch.ld_dispatch`).
+//!
+//! ```no_run
+//! use lamina::catalog::Catalog;
+//! use lamina::pmc::PerfCtlDescriptor;
+//!
+//! fn main() -> Result<(), lamina::Error> {
+//!     let cat = Catalog::native()?;
+//!     let pmc = PerfCtlDescriptor::new()
+//!         .set(0, cat.event("ex_ret_instr", 0)?)
+//!         .set(1, cat.event("ls_dispatch.ld_dispatch", 0)?);
+//!     Ok(())
+//! }
+//! ```
+//!
+//! ## Loading events at runtime
+//!
+//! The Linux `perf` tool ships JSON files with event definitions for each
+//! microarchitecture (under `tools/perf/pmu-events/arch/x86/amdzen*`).
+//! These can be loaded with [Catalog::from_pmu_events].
+//!
+//! [Event::desc] only knows about a handful of events. After a catalog is
+//! installed with [Catalog::install], it's also used to name and describe
+//! raw events (i.e. `Event::Undefined(0x29, 0x01)`):
+//!
+//! ```no_run
+//! use lamina::catalog::Catalog;
+//! use lamina::cpuid::Uarch;
+//! use lamina::event::Event;
+//!
+//! fn main() -> Result<(), lamina::Error> {
+//!     let dir = "linux/tools/perf/pmu-events/arch/x86/amdzen2";
+//!     let cat = Catalog::from_pmu_events(Uarch::Zen2, dir)?.install();
+//!     let e = Event::Undefined(0x29, 0x01);
+//!     assert_eq!(cat.name_of(&e).unwrap(), "ls_dispatch.ld_dispatch");
+//!     println!("{}", e.desc().desc);
+//!     Ok(())
+//! }
+//! ```
+//!
+//! ## Safety
+//!
+//! Counting an event from the wrong catalog doesn't fail in hardware: it
+//! just silently counts something else. Looking up an event in a catalog
+//! that doesn't match the running CPU returns [Error::CatalogMismatch].
+//! The hardware backends also check any named [Event] variants (which are
+//! defined for Zen 2) against the catalog for the running CPU (see
+//! [crate::pmc::PerfCtlDescriptor::check_native]).
+//!
+
+mod zen2;
+mod zen3;
+mod zen4;
+mod pmu_events;
+
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::cpuid::{ CpuInfo, Uarch };
+use crate::event::Event;
+use crate::error::{ Error, Op };
+
+type Err<T> = Result<T, Error>;
+
+/// Definition of a unit mask (a sub-event) for some event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnitMaskDef {
+    pub name: &'static str,
+    pub mask: u8,
+    pub desc: &'static str,
+}
+impl UnitMaskDef {
+    pub const fn new(name: &'static str, mask: u8, desc: &'static str)
+        -> Self
+    {
+        Self { name, mask, desc }
+    }
+}
+
+/// Definition of an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventDef {
+    pub name: &'static str,
+    /// The 12-bit event select code.
+    pub select: u16,
+    pub desc: &'static str,
+    /// Unit masks defined for this event (if any).
+    pub umasks: &'static [UnitMaskDef],
+    /// Unit mask used when none is selected.
+    pub default_umask: u8,
+    /// The PMU counting this event, if it isn't counted by the core PMCs
+    /// (i.e. `L3PMC` or `DFPMC`).
+    pub unit: Option<&'static str>,
+}
+impl EventDef {
+    pub const fn new(name: &'static str, select: u16, desc: &'static str,
+        umasks: &'static [UnitMaskDef]) -> Self
+    {
+        Self { name, select, desc, umasks, default_umask: 0, unit: None }
+    }
+
+    /// Returns true if this event is counted by the core PMCs.
+    pub fn is_core(&self) -> bool {
+        self.unit.is_none()
+    }
+
+    /// Return the union of all unit masks defined for this event.
+    pub fn valid_mask(&self) -> u8 {
+        self.umasks.iter().fold(0, |acc, m| acc | m.mask)
+    }
+
+    /// Returns true if some unit mask is valid for this event.
+    /// Any unit mask is valid for events without defined unit masks.
+    pub fn is_valid_umask(&self, umask: u8) -> bool {
+        self.umasks.is_empty() || (umask & !self.valid_mask()) == 0
+    }
+
+    /// Find a unit mask by name.
+    pub fn umask(&self, name: &str) -> Option<&UnitMaskDef> {
+        self.umasks.iter().find(|m| m.name == name)
+    }
+
+    /// Return the name of the unit mask selecting some single bit.
+    fn umask_bit_name(&self, bit: u8) -> Option<&str> {
+        self.umasks.iter().find(|m| m.mask == bit).map(|m| m.name)
+    }
+}
+
+/// A table of events defined for some microarchitecture.
+#[derive(Clone, Debug)]
+pub struct Catalog {
+    pub uarch: Uarch,
+    /// The document (or file) that these definitions were taken from.
+    pub source: Cow<'static, str>,
+    pub events: Cow<'static, [EventDef]>,
+}
+
+/// Events for Zen 2.
+pub static ZEN2: Catalog = Catalog {
+    uarch: Uarch::Zen2,
+    source: Cow::Borrowed(zen2::SOURCE),
+    events: Cow::Borrowed(zen2::EVENTS),
+};
+/// Events for Zen 3.
+pub static ZEN3: Catalog = Catalog {
+    uarch: Uarch::Zen3,
+    source: Cow::Borrowed(zen3::SOURCE),
+    events: Cow::Borrowed(zen3::EVENTS),
+};
+/// Events for Zen 4.
+pub static ZEN4: Catalog = Catalog {
+    uarch: Uarch::Zen4,
+    source: Cow::Borrowed(zen4::SOURCE),
+    events: Cow::Borrowed(zen4::EVENTS),
+};
+
+/// The catalog used to name and describe events (see [Catalog::install]).
+static INSTALLED: RwLock<Option<&'static Catalog>> = RwLock::new(None);
+
+impl Catalog {
+    /// Load event definitions from a directory of JSON event files from
+    /// the Linux `perf` tool (i.e. `pmu-events/arch/x86/amdzen2`).
+    pub fn from_pmu_events(uarch: Uarch, dir: impl AsRef<Path>)
+        -> Err<Catalog>
+    {
+        let dir = dir.as_ref();
+        let err = |path: &Path, err| Error::Io {
+            op: Op::ReadFile, path: path.display().to_string(), err
+        };
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(|e| err(dir, e))? {
+            let path = entry.map_err(|e| err(dir, e))?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut parser = pmu_events::Parser::default();
+        for path in paths.iter() {
+            let json = std::fs::read_to_string(path)
+                .map_err(|e| err(path, e))?;
+            parser.add(&json).map_err(|reason| Error::InvalidEventFile {
+                path: path.display().to_string(), reason
+            })?;
+        }
+        Ok(Catalog {
+            uarch,
+            source: Cow::Owned(dir.display().to_string()),
+            events: Cow::Owned(parser.finish()),
+        })
+    }
+
+    /// Use this catalog to name and describe events for the rest of the
+    /// program (see [Event::desc]), replacing any previously installed
+    /// catalog.
+    ///
+    /// The catalog is leaked (since it must live for the rest of the
+    /// program), including any catalog which is later replaced. This is
+    /// meant to be called once (i.e. when the program starts).
+    pub fn install(self) -> &'static Catalog {
+        let cat: &'static Catalog = Box::leak(Box::new(self));
+        *INSTALLED.write().unwrap() = Some(cat);
+        cat
+    }
+
+    /// Return the catalog installed with [Catalog::install] (if any).
+    pub fn installed() -> Option<&'static Catalog> {
+        *INSTALLED.read().unwrap()
+    }
+
+    /// Return the built-in catalog for some microarchitecture.
+    pub fn for_uarch(uarch: Uarch) -> &'static Catalog {
+        match uarch {
+            Uarch::Zen2 => &ZEN2,
+            Uarch::Zen3 => &ZEN3,
+            Uarch::Zen4 => &ZEN4,
+        }
+    }
+
+    /// Return the built-in catalog for the running CPU.
+    pub fn native() -> Err<&'static Catalog> {
+        let cpu = CpuInfo::get();
+        match cpu.uarch() {
+            Some(uarch) => Ok(Self::for_uarch(uarch)),
+            None => Err(Error::UnsupportedCpu { cpu: cpu.to_string() }),
+        }
+    }
+
+    /// Return an error if this catalog doesn't match the running CPU.
+    pub fn check(&self) -> Err<()> {
+        let cpu = CpuInfo::get();
+        match cpu.uarch() {
+            Some(uarch) if uarch == self.uarch => Ok(()),
+            Some(uarch) => Err(Error::CatalogMismatch {
+                catalog: self.uarch, cpu: uarch.to_string()
+            }),
+            None => Err(Error::CatalogMismatch {
+                catalog: self.uarch, cpu: cpu.to_string()
+            }),
+        }
+    }
+
+    /// Find an event counted by the core PMCs by name.
+    pub fn by_name(&self, name: &str) -> Option<&EventDef> {
+        self.events.iter().find(|e| e.is_core() && e.name == name)
+    }
+
+    /// Find an event counted by the core PMCs by event select code.
+    pub fn by_select(&self, select: u16) -> Option<&EventDef> {
+        self.events.iter().find(|e| e.is_core() && e.select == select)
+    }
+
+    /// Find the definition (and unit mask, if any) matching some [Event].
+    /// This only resolves named and raw events.
+    pub fn resolve(&self, e: &Event)
+        -> Option<(&EventDef, Option<&UnitMaskDef>)>
+    {
+        if !e.is_named() && !matches!(e, Event::Undefined(..)) {
+            return None;
+        }
+        let (select, umask) = e.convert();
+        let def = self.by_select(select)?;
+        Some((def, def.umasks.iter().find(|m| m.mask == umask)))
+    }
+
+    /// Return the name of some [Event] (i.e. `ls_dispatch.ld_dispatch`).
+    pub fn name_of(&self, e: &Event) -> Option<String> {
+        let (def, umask) = self.resolve(e)?;
+        Some(match umask {
+            Some(m) => format!("{}.{}", def.name, m.name),
+            None => def.name.to_string(),
+        })
+    }
+
+    /// Return a description of some [Event].
+    pub fn desc_of(&self, e: &Event) -> Option<&'static str> {
+        let (def, umask) = self.resolve(e)?;
+        Some(umask.map(|m| m.desc).filter(|d| !d.is_empty())
+            .unwrap_or(def.desc))
+    }
+
+    /// Look up an event by name without checking the running CPU.
+    ///
+    /// The name may also select a unit mask (i.e. `ls_dispatch.ld_dispatch`),
+    /// in which case it's combined with `umask`.
+    pub fn lookup(&self, name: &str, umask: u8) -> Err<Event> {
+        let (name, sub) = match name.split_once('.') {
+            Some((name, sub)) => (name, Some(sub)),
+            None => (name, None),
+        };
+        let def = self.by_name(name).ok_or_else(|| Error::UnknownEvent {
+            name: name.to_string(), uarch: self.uarch
+        })?;
+        let umask = match sub {
+            Some(sub) => match def.umask(sub) {
+                Some(m) => umask | m.mask,
+                None => return Err(Error::UnknownEvent {
+                    name: format!("{}.{}", name, sub), uarch: self.uarch
+                }),
+            },
+            None if umask == 0 => def.default_umask,
+            None => umask,
+        };
+        if !def.is_valid_umask(umask) {
+            return Err(Error::InvalidUnitMask {
+                event: def.name.to_string(), umask
+            });
+        }
+        Ok(Event::Undefined(def.select, umask))
+    }
+
+    /// Look up an event by name (see [Catalog::lookup]), returning an error
+    /// if this catalog doesn't match the running CPU.
+    pub fn event(&self, name: &str, umask: u8) -> Err<Event> {
+        self.check()?;
+        self.lookup(name, umask)
+    }
+
+    /// Check that some [Event] counts the same thing on this
+    /// microarchitecture as it does on Zen 2.
+    ///
+    /// Raw events ([Event::Undefined]), merge events, and software events
+    /// are always accepted. Named variants must exist in this catalog with
+    /// the same event select, and any unit mask bits must have the same
+    /// names as they do on Zen 2.
+    pub fn check_event(&self, e: &Event) -> Err<()> {
+        if !e.is_named() {
+            return Ok(());
+        }
+        let (select, umask) = e.convert();
+        let unknown = || Error::UnknownEvent {
+            name: format!("{:?}", e), uarch: self.uarch
+        };
+        let zen2 = ZEN2.by_select(select).ok_or_else(unknown)?;
+        let def = self.by_select(select).ok_or_else(unknown)?;
+        if !def.is_valid_umask(umask) {
+            return Err(Error::InvalidUnitMask {
+                event: def.name.to_string(), umask
+            });
+        }
+        if self.uarch == Uarch::Zen2 || zen2.umasks.is_empty() {
+            return Ok(());
+        }
+        // A zero unit mask usually selects all sub-events
+        let bits = if umask == 0 { zen2.valid_mask() } else { umask };
+        for bit in (0..8).map(|i| 1u8 << i).filter(|b| bits & b != 0) {
+            if zen2.umask_bit_name(bit) != def.umask_bit_name(bit) {
+                return Err(Error::InvalidUnitMask {
+                    event: def.name.to_string(), umask
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
@@ -0,0 +1,168 @@
+//! Parsing the JSON event files shipped with the Linux `perf` tool
+//! (under `tools/perf/pmu-events/arch/x86/amdzen*`).
+//!
+//! Each file is a list of entries. Entries with a dotted name (i.e.
+//! `ls_dispatch.ld_dispatch`) describe a unit mask for some event, and
+//! entries without a dot describe an event. Metrics (entries without an
+//! event code) are ignored.
+//!
+//! ```
+//! use lamina::catalog::Catalog;
+//! use lamina::cpuid::Uarch;
+//! use lamina::event::Event;
+//! use lamina::Error;
+//!
+//! let dir = std::env::temp_dir().join("lamina-pmu-events-doctest");
+//! std::fs::create_dir_all(&dir).unwrap();
+//! std::fs::write(dir.join("core.json"), r#"[
+//!     { "EventName": "ex_ret_instr", "EventCode": "0xC0",
+//!       "BriefDescription": "Retired instructions." },
+//!     { "EventName": "ls_dispatch.ld_dispatch", "EventCode": "0x29",
+//!       "UMask": "0x01", "BriefDescription": "Load dispatch." },
+//!     { "MetricName": "ipc", "MetricExpr": "ex_ret_instr / cycles" }
+//! ]"#).unwrap();
+//!
+//! let cat = Catalog::from_pmu_events(Uarch::Zen2, &dir).unwrap();
+//! assert_eq!(cat.events.len(), 2);
+//! assert_eq!(cat.lookup("ex_ret_instr", 0).unwrap(),
+//!     Event::Undefined(0xc0, 0x00));
+//! assert_eq!(cat.lookup("ls_dispatch.ld_dispatch", 0).unwrap(),
+//!     Event::Undefined(0x29, 0x01));
+//!
+//! // Event select codes for the core PMCs are only 12 bits wide
+//! std::fs::write(dir.join("core.json"), r#"[
+//!     { "EventName": "bogus", "EventCode": "0x1000" }
+//! ]"#).unwrap();
+//! let res = Catalog::from_pmu_events(Uarch::Zen2, &dir);
+//! assert!(matches!(res, Err(Error::InvalidEventFile { .. })));
+//! # std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+
+use serde::Deserialize;
+
+use super::{ EventDef, UnitMaskDef };
+
+/// A single entry in a JSON event file.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Entry {
+    event_name: Option<String>,
+    event_code: Option<String>,
+    #[serde(rename = "UMask")]
+    umask: Option<String>,
+    brief_description: Option<String>,
+    public_description: Option<String>,
+    unit: Option<String>,
+}
+
+/// An event which is still being assembled from entries.
+struct Partial {
+    name: String,
+    select: u16,
+    desc: Option<String>,
+    unit: Option<String>,
+    default_umask: u8,
+    umasks: Vec<UnitMaskDef>,
+}
+
+/// Collects event definitions from one or more JSON event files.
+#[derive(Default)]
+pub(super) struct Parser {
+    events: Vec<Partial>,
+}
+impl Parser {
+    /// Add the entries from some JSON event file.
+    pub fn add(&mut self, json: &str) -> Result<(), String> {
+        let entries: Vec<Entry> = serde_json::from_str(json)
+            .map_err(|e| e.to_string())?;
+        for entry in entries {
+            let (name, code) = match (entry.event_name, entry.event_code) {
+                (Some(name), Some(code)) => (name, code),
+                _ => continue,
+            };
+            // Event select codes are only 12 bits wide for the core PMCs
+            let max = if entry.unit.is_none() { 0xfff } else { 0xffff };
+            let select = parse_hex(&code)
+                .filter(|v| *v <= max)
+                .ok_or_else(|| format!("bad event code '{}' for {}",
+                    code, name))? as u16;
+            let umask = match &entry.umask {
+                Some(m) => parse_hex(m).filter(|v| *v <= 0xff)
+                    .ok_or_else(|| format!("bad unit mask '{}' for {}",
+                        m, name))? as u8,
+                None => 0,
+            };
+            let (desc, unit) = (
+                entry.brief_description.or(entry.public_description),
+                entry.unit,
+            );
+
+            let (base, sub) = match name.split_once('.') {
+                Some((base, sub)) => (base, Some(sub)),
+                None => (name.as_str(), None),
+            };
+            let idx = match self.events.iter()
+                .position(|p| p.name == base && p.unit == unit)
+            {
+                Some(idx) => idx,
+                None => {
+                    self.events.push(Partial {
+                        name: base.to_string(),
+                        select,
+                        desc: None,
+                        unit,
+                        default_umask: 0,
+                        umasks: Vec::new(),
+                    });
+                    self.events.len() - 1
+                },
+            };
+            let event = &mut self.events[idx];
+            match sub {
+                Some(sub) => event.umasks.push(UnitMaskDef::new(
+                    leak(sub.to_string()), umask, leak(desc.unwrap_or_default())
+                )),
+                None => {
+                    event.desc = desc;
+                    event.default_umask = umask;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the definitions for all events.
+    ///
+    /// The strings here are leaked, since [EventDef] only holds references
+    /// with a `'static` lifetime.
+    pub fn finish(self) -> Vec<EventDef> {
+        self.events.into_iter().map(|mut p| {
+            // Events defined only by their unit masks are described with
+            // the first sentence of a unit mask description, which is
+            // usually the name of the event
+            let desc = p.desc.take().unwrap_or_else(|| {
+                p.umasks.first()
+                    .and_then(|m| m.desc.split_once(". "))
+                    .map(|(first, _)| first.to_string())
+                    .unwrap_or_else(|| p.name.clone())
+            });
+            let mut def = EventDef::new(leak(p.name), p.select, leak(desc),
+                Box::leak(p.umasks.into_boxed_slice()));
+            def.default_umask = p.default_umask;
+            def.unit = p.unit.map(leak);
+            def
+        }).collect()
+    }
+}
+
+/// Parse a hexadecimal value (i.e. `0x1c0`).
+fn parse_hex(s: &str) -> Option<u32> {
+    let s = s.trim();
+    let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u32::from_str_radix(s, 16).ok()
+}
+
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
@@ -0,0 +1,85 @@
+//! Events for Zen 2 (see [crate::event::Event]).
+
+use super::{ EventDef as E, UnitMaskDef as M };
+
+pub const SOURCE: &str = 
+    "PPR for AMD Family 17h Model 71h B0 (56176 Rev 3.06 - Jul 17, 2019)";
+
+pub const EVENTS: &[E] = &[
+    E::new("ls_locks", 0x025, "Retired lock instructions", &[
+        M::new("spec_lock_hi_spec", 0x08, "High speculative cacheable lock"),
+        M::new("spec_lock_lo_spec", 0x04, "Low speculative cacheable lock"),
+        M::new("non_spec_lock", 0x02, "Non-speculative lock succeeded"),
+        M::new("bus_lock", 0x01, "Bus lock"),
+    ]),
+    E::new("ls_ret_cpuid", 0x027, "Retired CPUID instructions", &[]),
+    E::new("ls_dispatch", 0x029, "Load/store dispatch", &[
+        M::new("ld_st_dispatch", 0x04, "Load-op-store dispatched"),
+        M::new("store_dispatch", 0x02, "Stores dispatched"),
+        M::new("ld_dispatch", 0x01, "Loads dispatched"),
+    ]),
+    E::new("ls_smi_rx", 0x02b, "SMIs received", &[]),
+    E::new("ls_int_taken", 0x02c, "Interrupts taken", &[]),
+    E::new("ls_rdtsc", 0x02d, "Time stamp counter reads (speculative)", &[]),
+    E::new("ls_stlf", 0x035, "Store-to-load forwarding hits", &[]),
+    E::new("ls_pref_instr_disp", 0x04b, 
+        "Dispatched PREFETCH instructions (speculative)", &[
+        M::new("prefetch_nta", 0x04, "PREFETCHNTA"),
+        M::new("store_prefetch_w", 0x02, "PREFETCHW"),
+        M::new("load_prefetch_w", 0x01, "PREFETCH, PREFETCHT0/T1/T2"),
+    ]),
+    E::new("ls_not_halted_cyc", 0x076, "Cycles not in halt", &[]),
+    E::new("ic_cache_fill_l2", 0x082, 
+        "Instruction cache refills from L2", &[]),
+    E::new("bp_l1_btb_correct", 0x08a, 
+        "L1 BTB overrides existing prediction (speculative)", &[]),
+    E::new("bp_l2_btb_correct", 0x08b, 
+        "L2 BTB overrides existing prediction (speculative)", &[]),
+    E::new("bp_dyn_ind_pred", 0x08e, 
+        "Dynamic indirect branch predictions (speculative)", &[]),
+    E::new("bp_de_redirect", 0x091, 
+        "Branch redirects from decoder (speculative)", &[]),
+    E::new("de_dis_uop_queue_empty_di0", 0x0a9, 
+        "Cycles where the micro-op queue is empty", &[]),
+    E::new("de_src_op_disp", 0x0aa, 
+        "Source of ops dispatched from decoder", &[
+        M::new("op_cache", 0x02, "Ops dispatched from the op cache"),
+        M::new("x86_decoder", 0x01, "Ops dispatched from the decoder"),
+    ]),
+    E::new("de_dis_ops_from_decoder", 0x0ab, 
+        "Dispatched ops from decoder (speculative)", &[]),
+    E::new("de_dis_dispatch_token_stalls1", 0x0ae, 
+        "Dispatch resource stalls 1", &[
+        M::new("fp_misc_rsrc_stall", 0x80, "FP miscellaneous resource stall"),
+        M::new("fp_sch_rsrc_stall", 0x40, "FP scheduler resource stall"),
+        M::new("fp_reg_file_rsrc_stall", 0x20, "FP register file stall"),
+        M::new("taken_brnch_buffer_rsrc", 0x10, 
+            "Taken branch buffer resource stall"),
+        M::new("int_sched_misc_rsrc_stall", 0x08, 
+            "Integer scheduler miscellaneous resource stall"),
+        M::new("store_queue_rsrc_stall", 0x04, "Store queue resource stall"),
+        M::new("load_queue_rsrc_stall", 0x02, "Load queue resource stall"),
+        M::new("int_phy_reg_file_rsrc_stall", 0x01, 
+            "Integer physical register file resource stall"),
+    ]),
+    E::new("de_dis_dispatch_token_stalls0", 0x0af, 
+        "Dispatch resource stalls 0", &[
+        M::new("sc_agu_dispatch_stall", 0x40, "SC AGU dispatch stall"),
+        M::new("retire_token_stall", 0x20, "Retire queue resource stall"),
+        M::new("agsq_token_stall", 0x10, "AGSQ resource stall"),
+        M::new("alu_token_stall", 0x08, "ALU tokens total unavailable"),
+        M::new("alsq3_0_token_stall", 0x04, "ALSQ 3_0 resource stall"),
+        M::new("alsq2_token_stall", 0x02, "ALSQ 2 resource stall"),
+        M::new("alsq1_token_stall", 0x01, "ALSQ 1 resource stall"),
+    ]),
+    E::new("ex_ret_instr", 0x0c0, "Retired instructions", &[]),
+    E::new("ex_ret_cops", 0x0c1, "Retired ops", &[]),
+    E::new("ex_ret_brn", 0x0c2, "Retired branch instructions", &[]),
+    E::new("ex_ret_brn_misp", 0x0c3, 
+        "Retired branch instructions (mispredicted)", &[]),
+    E::new("ex_ret_brn_resync", 0x0c7, "Retired branch resyncs", &[]),
+    E::new("ex_ret_near_ret_mispred", 0x0c9, 
+        "Retired near-return instructions (mispredicted)", &[]),
+    E::new("ex_ret_brn_ind_misp", 0x0ca, 
+        "Retired indirect branch instructions (mispredicted)", &[]),
+];
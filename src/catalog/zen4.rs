@@ -0,0 +1,78 @@
+//! Events for Zen 4.
+
+use super::{ EventDef as E, UnitMaskDef as M };
+
+pub const SOURCE: &str = "Linux perf pmu-events (arch/x86/amdzen4)";
+
+pub const EVENTS: &[E] = &[
+    E::new("ls_locks", 0x025, "Retired lock instructions", &[
+        M::new("bus_lock", 0x01, "Bus lock"),
+    ]),
+    E::new("ls_ret_cpuid", 0x027, "Retired CPUID instructions", &[]),
+    E::new("ls_dispatch", 0x029, "Load/store dispatch", &[
+        M::new("ld_st_dispatch", 0x04, "Load-op-store dispatched"),
+        M::new("store_dispatch", 0x02, "Stores dispatched"),
+        M::new("ld_dispatch", 0x01, "Loads dispatched"),
+    ]),
+    E::new("ls_smi_rx", 0x02b, "SMIs received", &[]),
+    E::new("ls_int_taken", 0x02c, "Interrupts taken", &[]),
+    E::new("ls_stlf", 0x035, "Store-to-load forwarding hits", &[]),
+    E::new("ls_pref_instr_disp", 0x04b, 
+        "Dispatched PREFETCH instructions (speculative)", &[
+        M::new("prefetch_nta", 0x04, "PREFETCHNTA"),
+        M::new("store_prefetch_w", 0x02, "PREFETCHW"),
+        M::new("load_prefetch_w", 0x01, "PREFETCH, PREFETCHT0/T1/T2"),
+    ]),
+    E::new("ls_not_halted_cyc", 0x076, "Cycles not in halt", &[]),
+    E::new("ic_cache_fill_l2", 0x082, 
+        "Instruction cache refills from L2", &[]),
+    E::new("ic_cache_fill_sys", 0x083, 
+        "Instruction cache refills from system memory", &[]),
+    E::new("bp_l2_btb_correct", 0x08b, 
+        "L2 BTB overrides existing prediction (speculative)", &[]),
+    E::new("bp_dyn_ind_pred", 0x08e, 
+        "Dynamic indirect branch predictions (speculative)", &[]),
+    E::new("bp_de_redirect", 0x091, 
+        "Branch redirects from decoder (speculative)", &[]),
+    E::new("de_op_queue_empty", 0x0a9, 
+        "Cycles where the op queue is empty", &[]),
+    E::new("de_src_op_disp", 0x0aa, 
+        "Source of ops dispatched from decoder", &[
+        M::new("op_cache", 0x02, "Ops dispatched from the op cache"),
+        M::new("x86_decoder", 0x01, "Ops dispatched from the decoder"),
+    ]),
+    E::new("de_dis_ops_from_decoder", 0x0ab, 
+        "Types of ops dispatched from decoder", &[
+        M::new("disp_op_type.any_integer_dispatch", 0x08, 
+            "Integer ops dispatched"),
+        M::new("disp_op_type.any_fp_dispatch", 0x04, 
+            "Floating-point ops dispatched"),
+    ]),
+    E::new("de_dispatch_stall_cycle_dynamic_tokens_part1", 0x0ae, 
+        "Dispatch resource stalls 1", &[
+        M::new("fp_sch_rsrc_stall", 0x40, "FP scheduler resource stall"),
+        M::new("taken_brnch_buffer_rsrc", 0x10, 
+            "Taken branch buffer resource stall"),
+        M::new("store_queue_rsrc_stall", 0x04, "Store queue resource stall"),
+        M::new("load_queue_rsrc_stall", 0x02, "Load queue resource stall"),
+        M::new("int_phy_reg_file_rsrc_stall", 0x01, 
+            "Integer physical register file resource stall"),
+    ]),
+    E::new("de_dispatch_stall_cycle_dynamic_tokens_part2", 0x0af, 
+        "Dispatch resource stalls 2", &[
+        M::new("retire_token_stall", 0x20, "Retire queue resource stall"),
+        M::new("int_sch3_token_stall", 0x08, "Integer scheduler 3 stall"),
+        M::new("int_sch2_token_stall", 0x04, "Integer scheduler 2 stall"),
+        M::new("int_sch1_token_stall", 0x02, "Integer scheduler 1 stall"),
+        M::new("int_sch0_token_stall", 0x01, "Integer scheduler 0 stall"),
+    ]),
+    E::new("ex_ret_instr", 0x0c0, "Retired instructions", &[]),
+    E::new("ex_ret_ops", 0x0c1, "Retired ops", &[]),
+    E::new("ex_ret_brn", 0x0c2, "Retired branch instructions", &[]),
+    E::new("ex_ret_brn_misp", 0x0c3, 
+        "Retired branch instructions (mispredicted)", &[]),
+    E::new("ex_ret_near_ret_mispred", 0x0c9, 
+        "Retired near-return instructions (mispredicted)", &[]),
+    E::new("ex_ret_brn_ind_misp", 0x0ca, 
+        "Retired indirect branch instructions (mispredicted)", &[]),
+];
@@ -2,6 +2,7 @@
 //!
 //! These are mostly all wrappers around the [dynasmrt::dynasm] macro, used 
 //! for easily parameterizing different kinds of gadgets/idioms in assembly.
+//! Macros which generate an entire test are built on [crate::builder].
 //!
 //! ## Safety
 //!
@@ -20,6 +21,33 @@
 //! the Zen 2 microarchitecture is implemented. Any compatibility with other
 //! machines is *not expected* and *not guaranteed*.
 //!
+//! ## Errors
+//!
+//! Macros which generate an entire test evaluate to an [EmitResult].
+//!
+
+use dynasmrt::{ Assembler, ExecutableBuffer, x64::X64Relocation };
+use crate::error::{ Error, Op };
+
+/// The result of generating an entire test.
+pub type EmitResult = Result<ExecutableBuffer, Error>;
+
+/// Create a new [Assembler].
+pub fn new_assembler() -> Result<Assembler<X64Relocation>, Error> {
+    Assembler::<X64Relocation>::new().map_err(|e| Error::Codegen { 
+        op: Op::NewAssembler, reason: e.to_string() 
+    })
+}
+
+/// Resolve all labels and convert an [Assembler] into an [ExecutableBuffer].
+pub fn finalize(mut asm: Assembler<X64Relocation>) -> EmitResult {
+    asm.commit().map_err(|e| Error::Codegen { 
+        op: Op::Finalize, reason: e.to_string() 
+    })?;
+    asm.finalize().map_err(|_| Error::Codegen { 
+        op: Op::Finalize, reason: "couldn't make buffer executable".to_string()
+    })
+}
 
 
 /// Common prologue for emitted code. 
@@ -128,9 +156,9 @@ macro_rules! emit_rdpru_rdx { ($asm:ident, $($tail:tt)*) => {
 /// loads to miss in the cache.
 ///
 /// Returns the difference (number of APERF cycles elapsed) in RAX.
+/// This is a wrapper around [crate::builder::hwong_gadget].
 ///
 /// ## Register use
-/// - RCX cannot be overwritten (RDPRU depends on RCX=1)
 /// - RDI/RSI cannot be overwritten (pointers for high-latency loads)
 /// - R13 cannot be overwritten (loop counter)
 /// - R14 cannot be overwritten (holds the initial value from APERF)
@@ -141,177 +169,92 @@ macro_rules! emit_hwong_gadget_test {
     ($tgt_ptr1:ident, $tgt_ptr2:ident, $free_ptr:ident, 
      $loop_iters:expr, $outer_unroll:expr, $inner_unroll:expr,
      body_a($($body_a:tt)*), body_b($($body_b:tt)*)
-    ) => { {
-        let mut asm = Assembler::<X64Relocation>::new().unwrap();
-        emit_push_abi!(asm);
-        dynasm!(asm
-            // We have to keep 1 in RCX to read APERF with RDPRU
-            // (this means measured code must not use RCX)
-            ; mov       rcx, 1
-            ; mov       rdi, QWORD $tgt_ptr1 as _
-            ; mov       rsi, QWORD $tgt_ptr2 as _
-            ; xor       r14, r14
-            ; mov       r15, QWORD $free_ptr as _
-        );
-
-        // Take the first measurement.
-        emit_rdpru_rdx!(asm,
-            ; sub       r14, rdx
-        );
+    ) => { 
+        $crate::builder::hwong_gadget(
+            $tgt_ptr1 as *const usize, $tgt_ptr2 as *const usize, 
+            $free_ptr as *const usize,
+            $loop_iters, $outer_unroll, $inner_unroll,
+            |asm| { dynasm!(asm $($body_a)*); },
+            |asm| { dynasm!(asm $($body_b)*); },
+        ).map(|test| test.buf)
+    }
+}
 
-        emit_loop_reg!(asm, r13, $loop_iters, {
-            for _ in 0 ..$outer_unroll {
-                dynasm!(asm ; mov   rdi, [rdi]);
-                for _ in 0..$inner_unroll { dynasm!(asm $($body_a)*); }
-                dynasm!(asm ; mov   rsi, [rsi]);
-                for _ in 0..$inner_unroll { dynasm!(asm $($body_b)*); }
-            }
-        });
 
-        // Take the second measurement.
-        emit_rdpru_rdx!(asm,
-            ; add       r14, rdx
-            ; mov       rax, r14
-        );
+/// A function called by emitted code in place of RDPMC. 
+///
+/// Takes some opaque pointer and the counter index (the value of ECX), and 
+/// returns the value of the counter.
+pub type ReadStubFn = extern "C" fn(*const u8, usize) -> u64;
 
-        emit_pop_abi_ret!(asm);
-        asm.finalize().unwrap()
-    } }
+/// A [ReadStubFn] and the opaque pointer passed to it.
+#[derive(Clone, Copy)]
+pub struct ReadStub {
+    pub func: ReadStubFn,
+    pub arg: *const u8,
 }
 
-
 /// Emit a test utilizing all six PMC registers to capture some result data.
 ///
+/// This is a wrapper around [crate::builder::TestBuilder::pmc].
+///
 /// ## Conventions
 /// r15 is reserved for a pointer to the set of results.
 /// r14, r13, r12, r11, r10, and r9 are reserved for RDPMC results.
 ///
+/// On entry, each element in the set of results holds the RDPMC index 
+/// (the value of ECX) used to read the corresponding counter. Counters whose
+/// index is [PMCTest::NO_RDPMC] are skipped (and left to the caller).
+///
+/// Prefixing the body with `stub(...)` replaces each RDPMC with a call to 
+/// some [ReadStub], i.e.
+/// `emit_rdpmc_test_all!(stub(ctx.read_stub()), ; nop ; nop)`.
+/// The stub clobbers RDI, RSI, and R8 (R9-R11 are preserved).
+///
 #[macro_export]
 macro_rules! emit_rdpmc_test_all {
-    ($($body:tt)*) => { {
-        let mut asm = Assembler::<X64Relocation>::new().unwrap();
-        dynasm!(asm
-            ; .arch     x64
-            ; push      rbp
-            ; push      rbx
-            ; push      rdi
-            ; push      rsi
-            ; push      r12
-            ; push      r13
-            ; push      r14
-            ; push      r15
-            ; mfence
-            ; lfence
-
-            ; mov       r15, rdi
-            ; xor       rax, rax
-            ; xor       rbx, rbx
-            ; xor       rcx, rcx
-            ; xor       rdx, rdx
-            ; xor       rsi, rsi
-            ; xor       rdi, rdi
-            ; xor       rbp, rbp
-            ; xor        r8, r8
-            ; xor        r9, r9
-            ; xor       r10, r10
-            ; xor       r11, r11
-            ; xor       r12, r12
-            ; xor       r13, r13
-            ; xor       r14, r14
-            //; xor       r15, r15
-            ; mfence
-            ; lfence
-        );
-
-        // Take some measurements
-        dynasm!(asm
-            ; mov rcx, 5 ; lfence ; rdpmc ; lfence ; sub r14, rax
-            ; mov rcx, 4 ; lfence ; rdpmc ; lfence ; sub r13, rax
-            ; mov rcx, 3 ; lfence ; rdpmc ; lfence ; sub r12, rax
-            ; mov rcx, 2 ; lfence ; rdpmc ; lfence ; sub r11, rax
-            ; mov rcx, 1 ; lfence ; rdpmc ; lfence ; sub r10, rax
-            ; mov rcx, 0 ; lfence ; rdpmc ; lfence ; sub  r9, rax
-        );
-
-        // Do something.
-        // At this point, RAX, RCX, RDX, and R9-R14 have been used.
-
-        dynasm!(asm 
-            $($body)*
-        );
-
-        // Take another set of measurements and compute the difference
-        dynasm!(asm
-            ; mov rcx, 0 ; lfence ; rdpmc ; lfence ; add  r9, rax
-            ; mov rcx, 1 ; lfence ; rdpmc ; lfence ; add r10, rax
-            ; mov rcx, 2 ; lfence ; rdpmc ; lfence ; add r11, rax
-            ; mov rcx, 3 ; lfence ; rdpmc ; lfence ; add r12, rax
-            ; mov rcx, 4 ; lfence ; rdpmc ; lfence ; add r13, rax
-            ; mov rcx, 5 ; lfence ; rdpmc ; lfence ; add r14, rax
-        );
-
-        // Write the results back to memory
-        dynasm!(asm
-            ; mov [r15 + 0x00], r9
-            ; mov [r15 + 0x08], r10
-            ; mov [r15 + 0x10], r11
-            ; mov [r15 + 0x18], r12
-            ; mov [r15 + 0x20], r13
-            ; mov [r15 + 0x28], r14
-        );
-
-        dynasm!(asm
-            ; pop r15
-            ; pop r14
-            ; pop r13
-            ; pop r12
-            ; pop rsi
-            ; pop rdi
-            ; pop rbx
-            ; pop rbp
-            ; mfence
-            ; ret
-            ; lfence
-        );
-        asm.finalize().unwrap()
-    } }
+    (@inner $stub:expr, $($body:tt)*) => { 
+        $crate::builder::TestBuilder::pmc().and_then(|b| b
+            .read_stub($stub)
+            .prologue()
+            .start()
+            .body(|asm| { dynasm!(asm $($body)*); })
+            .stop()
+            .epilogue()
+            .finish()
+        ).map(|test| test.buf)
+    };
+    (stub($stub:expr), $($body:tt)*) => { 
+        emit_rdpmc_test_all!(@inner Some($stub), $($body)*)
+    };
+    ($($body:tt)*) => { 
+        emit_rdpmc_test_all!(@inner None, $($body)*)
+    };
 }
 
 
 /// Emit a test utilizing a single counter to capture a single event.
 ///
-/// Returns the difference (number of events counted) in RAX.
+/// `$ctr` is used both as the counter index and the value of ECX for RDPMC
+/// (following the conventions of the kernel module). Returns the difference 
+/// (number of events counted) in RAX.
 ///
-/// NOTE: This is unusable right now because [PMCTest] (and implicitly,
-/// [PMCTestFn] assume that emitted code writes all six counters to some
-/// array given by a pointer.
+/// This is a wrapper around [crate::builder::TestBuilder::single].
+/// Use [PMCTest::new_single] to run the resulting code.
 ///
 #[macro_export]
 macro_rules! emit_rdpmc_test_single {
-    ($ctr:expr, $($body:tt)*) => { {
-        assert!($ctr < 6);
-        let mut asm = Assembler::<X64Relocation>::new().unwrap();
-        emit_push_abi!(asm);
-        dynasm!(asm
-            ; mov       ecx, $ctr as _
-            ; lfence
-            ; rdpmc
-            ; lfence
-            ; sub r15, rax
-
-            $($body)*
-
-            ; mov       ecx, $ctr as _
-            ; lfence
-            ; rdpmc
-            ; lfence
-            ; add r15, rax
-            ; mov rax, r15
-            ; mfence
-        );
-        emit_pop_abi_ret!(asm);
-        asm.finalize().unwrap()
-    } }
+    ($ctr:expr, $($body:tt)*) => { 
+        $crate::builder::TestBuilder::single($ctr as usize, $ctr as u32)
+            .and_then(|b| b
+                .prologue()
+                .start()
+                .body(|asm| { dynasm!(asm $($body)*); })
+                .stop()
+                .epilogue()
+                .finish()
+        ).map(|test| test.buf)
+    }
 }
 
 
@@ -0,0 +1,113 @@
+//! Identifying the running CPU with `CPUID`.
+
+use core::arch::x86_64::__cpuid;
+
+/// A microarchitecture with a known set of PMC events (see
+/// [crate::catalog::Catalog]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Uarch {
+    /// AMD Family 17h (models 30h and above).
+    Zen2,
+    /// AMD Family 19h (models 00h-0Fh, 20h-5Fh).
+    Zen3,
+    /// AMD Family 19h (models 10h-1Fh, 60h-7Fh, A0h-AFh).
+    Zen4,
+}
+impl Uarch {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Uarch::Zen2 => "Zen 2",
+            Uarch::Zen3 => "Zen 3",
+            Uarch::Zen4 => "Zen 4",
+        }
+    }
+}
+impl std::fmt::Display for Uarch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_str())
+    }
+}
+
+/// Vendor, family, and model of some CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuInfo {
+    /// The vendor identification string (i.e. `AuthenticAMD`).
+    pub vendor: [u8; 12],
+    /// The effective family (base family plus extended family).
+    pub family: u32,
+    /// The effective model (extended model and base model).
+    pub model: u32,
+    pub stepping: u32,
+}
+impl CpuInfo {
+    /// Read the vendor, family, and model of the running CPU.
+    pub fn get() -> Self {
+        let (leaf0, leaf1) = (__cpuid(0), __cpuid(1));
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        let base_family = (leaf1.eax >> 8) & 0xf;
+        let base_model  = (leaf1.eax >> 4) & 0xf;
+        let ext_family  = (leaf1.eax >> 20) & 0xff;
+        let ext_model   = (leaf1.eax >> 16) & 0xf;
+        let (family, model) = if base_family == 0xf {
+            (base_family + ext_family, (ext_model << 4) | base_model)
+        } else if base_family == 0x6 {
+            (base_family, (ext_model << 4) | base_model)
+        } else {
+            (base_family, base_model)
+        };
+        Self { vendor, family, model, stepping: leaf1.eax & 0xf }
+    }
+
+    /// Returns true if this is an AMD CPU.
+    pub fn is_amd(&self) -> bool {
+        &self.vendor == b"AuthenticAMD"
+    }
+
+    /// Return the vendor identification string.
+    pub fn vendor_str(&self) -> &str {
+        std::str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// Return the microarchitecture for this CPU (if it's supported).
+    pub fn uarch(&self) -> Option<Uarch> {
+        if !self.is_amd() {
+            return None;
+        }
+        match (self.family, self.model) {
+            (0x17, 0x30..=0xff) => Some(Uarch::Zen2),
+            (0x19, 0x00..=0x0f) | (0x19, 0x20..=0x5f) => Some(Uarch::Zen3),
+            (0x19, 0x10..=0x1f) | (0x19, 0x60..=0x7f)
+                | (0x19, 0xa0..=0xaf) => Some(Uarch::Zen4),
+            _ => None,
+        }
+    }
+}
+/// Return the width (in bits) of the general-purpose counters on the
+/// running CPU.
+///
+/// Intel CPUs report the width in CPUID leaf 0Ah. AMD CPUs don't report the
+/// width at all, and the counters are always 48 bits wide (see
+/// [crate::pmc::COUNTER_WIDTH]), which is also the fallback here.
+pub fn counter_width() -> u32 {
+    let max_leaf = __cpuid(0).eax;
+    if max_leaf >= 0xa && !CpuInfo::get().is_amd() {
+        let width = (__cpuid(0xa).eax >> 16) & 0xff;
+        if width != 0 && width <= 64 {
+            return width;
+        }
+    }
+    crate::pmc::COUNTER_WIDTH
+}
+
+impl std::fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} family {:02x}h model {:02x}h stepping {}",
+            self.vendor_str(), self.family, self.model, self.stepping
+        )
+    }
+}
+
@@ -1,8 +1,56 @@
 //! Conventions for managing interactions with the kernel module.
+//!
+//! The [PMCBackend] trait describes some mechanism for programming the PMCs
+//! and obtaining counter values. [PMCContext] is the implementation backed 
+//! by the kernel module; see [crate::perf::PerfEventContext] for an 
+//! implementation backed by the Linux `perf_event_open()` interface.
 
 use crate::pmc;
+use crate::perf::PerfEventContext;
+use crate::error::{ Error, Op };
+use nix::errno::Errno;
 
-type Err<T> = Result<T, &'static str>;
+type Err<T> = Result<T, Error>;
+
+/// Interface to some mechanism for programming and reading the PMCs.
+pub trait PMCBackend {
+    /// Write a new [pmc::PerfCtlDescriptor] (starting the counters).
+    fn write(&mut self, d: &pmc::PerfCtlDescriptor) -> Err<()>;
+
+    /// Clear (zero out) the current [pmc::PerfCtlDescriptor].
+    fn clear(&mut self) -> Err<()>;
+
+    /// Return the most recent [pmc::PerfCtlDescriptor].
+    fn desc(&self) -> &pmc::PerfCtlDescriptor;
+
+    /// Return the index (the value of ECX) used to read a particular counter
+    /// with RDPMC, or [None] if the counter cannot be read with RDPMC.
+    fn rdpmc_index(&self, idx: usize) -> Option<u32>;
+
+    /// Read the current value of a counter without using RDPMC.
+    fn read_ctr(&self, idx: usize) -> Err<u64>;
+}
+
+/// Open the kernel module if it's loaded, otherwise fall back to using
+/// `perf_event_open()`.
+///
+/// Only a missing character device means that the kernel module isn't
+/// loaded; any other error (i.e. insufficient permissions) is returned.
+pub fn open_backend() -> Err<Box<dyn PMCBackend>> {
+    open_backend_on(PMCContext::DEFAULT_CORE)
+}
+
+/// Like [open_backend], but programming the counters on a particular core
+/// when using the kernel module (see [PMCContext::on_core]).
+pub fn open_backend_on(core: usize) -> Err<Box<dyn PMCBackend>> {
+    match PMCContext::on_core(core) {
+        Ok(ctx) => Ok(Box::new(ctx)),
+        Err(Error::Device { op: Op::Open, errno: Errno::ENOENT, .. }) => {
+            Ok(Box::new(PerfEventContext::new()?))
+        },
+        Err(e) => Err(e),
+    }
+}
 
 /// Kernel module FFI - a set of PERF_CTL values.
 #[repr(C)]
@@ -15,6 +63,16 @@ nix::ioctl_write_ptr_bad! {
     lamina_writectl, PMCContext::CMD_WRITECTL, LaminaMsg
 }
 
+nix::ioctl_read_bad! {
+    /// Kernel module FFI - read the current set of PERF_CTL values.
+    lamina_readctl, PMCContext::CMD_READCTL, LaminaMsg
+}
+
+nix::ioctl_write_int_bad! {
+    /// Kernel module FFI - set the target CPU for this file descriptor.
+    lamina_setcpu, PMCContext::CMD_SETCPU
+}
+
 /// Container for the current state of the PMCs.
 ///
 /// ## Safety
@@ -27,9 +85,16 @@ nix::ioctl_write_ptr_bad! {
 /// probably also want to set/write an empty [pmc::PerfCtlDescriptor] before 
 /// closing the file descriptor (to explicitly stop the counters).
 ///
+/// The kernel module programs the counters on a single target core (see
+/// [PMCContext::on_core]). Since RDPMC reads the counters on the core it
+/// runs on, writing a descriptor fails unless the calling thread is pinned
+/// to the target core (see [crate::util::pin_to_core]).
+///
 pub struct PMCContext {
     /// File descriptor for the character device.
     fd: i32,
+    /// The core where the counters are programmed.
+    core: usize,
     /// The most recent set of PERF_CTL values.
     desc: pmc::PerfCtlDescriptor,
 }
@@ -42,68 +107,178 @@ impl PMCContext {
     /// `ioctl()` command for writing a new set of PMC events.
     pub const CMD_WRITECTL: usize = 0x0000_1000;
 
-    /// Create a new context.
+    /// `ioctl()` command for reading the current set of PMC events.
+    pub const CMD_READCTL: usize = 0x0000_1001;
+
+    /// `ioctl()` command for changing the target core.
+    pub const CMD_SETCPU: usize = 0x0000_1002;
+
+    /// The default target core (for a newly-opened file descriptor).
+    pub const DEFAULT_CORE: usize = 0;
+
+    /// Create a new context (programming the counters on
+    /// [PMCContext::DEFAULT_CORE]).
     pub fn new() -> Err<Self> {
+        Self::on_core(Self::DEFAULT_CORE)
+    }
+
+    /// Create a new context which programs the counters on some core.
+    pub fn on_core(core: usize) -> Err<Self> {
         use nix::sys::stat::Mode;
         use nix::fcntl::{ open, OFlag };
-        use nix::errno::Errno;
 
-        let fd = match open(Self::CHARDEV, OFlag::O_RDWR, Mode::S_IRWXU) {
-            Ok(fd) => {
-                Ok(fd)
-            },
-            Err(e) => match e {
-                Errno::ENOENT => Err("Kernel module not loaded?"),
-                Errno::EACCES => Err("Permission denied?"),
-                _ => panic!("unhandled error {}", e),
-            },
-        }?;
-        Ok(Self { desc: pmc::PerfCtlDescriptor::new(), fd, })
+        let fd = open(Self::CHARDEV, OFlag::O_RDWR, Mode::S_IRWXU)
+            .map_err(|errno| Error::Device { 
+                op: Op::Open, path: Self::CHARDEV, errno 
+            })?;
+        // The kernel module already targets the default core
+        if core != Self::DEFAULT_CORE {
+            if let Err(errno) = unsafe { lamina_setcpu(fd, core as i32) } {
+                // Close the fd without clearing anything (dropping a context
+                // would clear the counters on the default core, which might
+                // be in use by someone else)
+                let _ = nix::unistd::close(fd);
+                return Err(Error::Device { 
+                    op: Op::SetCpu, path: Self::CHARDEV, errno 
+                });
+            }
+        }
+        Ok(Self { desc: pmc::PerfCtlDescriptor::new(), fd, core })
+    }
+
+    /// Return the core where the counters are programmed.
+    pub fn core(&self) -> usize { self.core }
+
+    /// Check that the calling thread is pinned to the target core.
+    pub fn check_affinity(&self) -> Err<()> {
+        let cores = crate::util::affinity()?;
+        if cores != [self.core] {
+            return Err(Error::AffinityMismatch { core: self.core, cores });
+        }
+        Ok(())
     }
 
     /// Send the associated [pmc::PerfCtlDescriptor] to the kernel module.
     fn do_ioctl(&mut self) -> Err<()> {
+        if self.desc.events.iter().flatten().any(|e| e.is_software()) {
+            return Err(Error::Unsupported { 
+                op: Op::WriteCtl, 
+                reason: "software events require perf_event_open()",
+            });
+        }
         let mut msg = LaminaMsg { ctl: [0; 6] };
         for (idx, val) in msg.ctl.iter_mut().enumerate() {
             *val = self.desc.get(idx);
         }
-        unsafe {
-            match lamina_writectl(self.fd, &msg as *const LaminaMsg) {
-                Ok(res) => {
-                    if res < 0 {
-                        return Err("ioctl() returned non-zero");
-                    }
-                },
-                Err(e) => {
-                    println!("{}", e);
-                    return Err("ioctl() unspecified error");
-                }
+        let res = unsafe { lamina_writectl(self.fd, &msg as *const LaminaMsg) };
+        match res {
+            Ok(0) => Ok(()),
+            // The kernel module returns the number of bytes it couldn't copy
+            Ok(_) => Err(Error::Device { 
+                op: Op::WriteCtl, path: Self::CHARDEV, errno: Errno::EFAULT 
+            }),
+            Err(errno) => Err(Error::Device { 
+                op: Op::WriteCtl, path: Self::CHARDEV, errno 
+            }),
+        }
+    }
+
+    /// Read the raw `PERF_CTL` values currently programmed on the target
+    /// CPU.
+    pub fn read_raw(&self) -> Err<[u64; 6]> {
+        let mut msg = LaminaMsg { ctl: [0; 6] };
+        let res = unsafe { 
+            lamina_readctl(self.fd, &mut msg as *mut LaminaMsg) 
+        };
+        match res {
+            Ok(0) => Ok(msg.ctl),
+            Ok(_) => Err(Error::Device { 
+                op: Op::ReadCtl, path: Self::CHARDEV, errno: Errno::EFAULT 
+            }),
+            Err(errno) => Err(Error::Device { 
+                op: Op::ReadCtl, path: Self::CHARDEV, errno 
+            }),
+        }
+    }
+
+    /// Read the `PERF_CTL` values currently programmed on the target CPU.
+    ///
+    /// Entries with the same event select and unit mask as the most recent
+    /// descriptor keep the same event. Otherwise, the event is decoded from
+    /// the `PERF_CTL` value (see [pmc::PerfCtl::event]).
+    pub fn read(&self) -> Err<pmc::PerfCtlDescriptor> {
+        let mut desc = pmc::PerfCtlDescriptor::new();
+        for (idx, val) in self.read_raw()?.iter().enumerate() {
+            if *val == 0 {
+                continue;
+            }
+            let ctl = pmc::PerfCtl(*val as usize);
+            desc.events[idx] = match self.desc.events[idx] {
+                Some(e) if e.convert() == ctl.event().convert() => Some(e),
+                _ => Some(ctl.event()),
+            };
+            desc.ctl[idx] = Some(ctl);
+        }
+        Ok(desc)
+    }
+
+    /// Check that the `PERF_CTL` values on the target CPU match the most 
+    /// recent descriptor (i.e. they haven't been changed by another tool).
+    pub fn verify(&self) -> Err<()> {
+        for (idx, actual) in self.read_raw()?.iter().enumerate() {
+            let expected = self.desc.get(idx);
+            if *actual != expected {
+                return Err(Error::CtlMismatch { 
+                    idx, expected, actual: *actual 
+                });
             }
         }
         Ok(())
     }
 
+}
+
+impl PMCBackend for PMCContext {
+    /// Write a new [pmc::PerfCtlDescriptor] for this context.
+    fn write(&mut self, d: &pmc::PerfCtlDescriptor) -> Err<()> {
+        d.validate()?;
+        self.check_affinity()?;
+        self.desc = *d;
+        self.do_ioctl()
+    }
+
     /// Clear (zero out) the [pmc::PerfCtlDescriptor] for this context.
-    pub fn clear(&mut self) -> Err<()> {
+    fn clear(&mut self) -> Err<()> {
         self.desc.clear_all();
         self.do_ioctl()
     }
 
-    /// Write a new [pmc::PerfCtlDescriptor] for this context.
-    pub fn write(&mut self, d: &pmc::PerfCtlDescriptor) -> Err<()> {
-        self.desc = *d;
-        self.do_ioctl()
+    fn desc(&self) -> &pmc::PerfCtlDescriptor { &self.desc }
+
+    /// The kernel module always programs counter `idx` in `PERF_CTL[idx]`.
+    fn rdpmc_index(&self, idx: usize) -> Option<u32> {
+        assert!(idx < 6);
+        Some(idx as u32)
+    }
+
+    fn read_ctr(&self, _idx: usize) -> Err<u64> {
+        Err(Error::Unsupported { 
+            op: Op::ReadCtr, 
+            reason: "the kernel module only supports reading with RDPMC",
+        })
     }
 }
 
 impl std::ops::Drop for PMCContext {
     fn drop(&mut self) {
         use nix::unistd::close;
-        self.clear().unwrap();
+        if let Err(e) = self.clear() {
+            eprintln!("[!] Couldn't clear counters: {}", e);
+        }
         match close(self.fd) {
             Ok(_) => {},
             Err(_) => {
-                println!("[!] Couldn't close lamina file descriptor?");
+                eprintln!("[!] Couldn't close lamina file descriptor?");
             },
         }
     }
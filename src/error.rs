@@ -0,0 +1,230 @@
+//! Error type shared by fallible operations in this crate.
+
+use nix::errno::Errno;
+use crate::cpuid::Uarch;
+
+/// An operation which may fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Opening a file or device.
+    Open,
+    /// Closing a file or device.
+    Close,
+    /// Writing a new set of `PERF_CTL` values.
+    WriteCtl,
+    /// Reading the current set of `PERF_CTL` values.
+    ReadCtl,
+    /// Changing the target CPU for the kernel module.
+    SetCpu,
+    /// Reading the value of a counter.
+    ReadCtr,
+    /// Opening an event with `perf_event_open()`.
+    PerfEventOpen,
+    /// Mapping the `perf_event_mmap_page` for an event.
+    Mmap,
+    /// Changing the CPU affinity of the current thread.
+    SetAffinity,
+    /// Reading the CPU affinity of the current thread.
+    GetAffinity,
+    /// Allocating a new assembler.
+    NewAssembler,
+    /// Resolving labels and creating an executable buffer.
+    Finalize,
+    /// Encoding a block of instructions (see [crate::asm]).
+    Assemble,
+    /// Writing to a file.
+    WriteFile,
+    /// Reading a file (or directory).
+    ReadFile,
+}
+impl Op {
+    pub fn to_str(&self) -> &'static str {
+        use Op::*;
+        match self {
+            Open => "open",
+            Close => "close",
+            WriteCtl => "write PERF_CTL",
+            ReadCtl => "read PERF_CTL",
+            SetCpu => "set target CPU",
+            ReadCtr => "read counter",
+            PerfEventOpen => "perf_event_open",
+            Mmap => "mmap",
+            SetAffinity => "set affinity",
+            GetAffinity => "get affinity",
+            NewAssembler => "create assembler",
+            Finalize => "finalize assembler",
+            Assemble => "assemble instructions",
+            WriteFile => "write file",
+            ReadFile => "read file",
+        }
+    }
+}
+
+/// Errors returned by this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// An operation on the kernel module's character device failed.
+    Device { op: Op, path: &'static str, errno: Errno },
+    /// An operation on an event opened with `perf_event_open()` failed.
+    Perf { op: Op, errno: Errno },
+    /// Couldn't pin the current thread to some core.
+    Affinity { core: usize, errno: Errno },
+    /// Couldn't generate code.
+    Codegen { op: Op, reason: String },
+    /// An operation is not supported by some backend.
+    Unsupported { op: Op, reason: &'static str },
+    /// An operation on some file failed.
+    Io { op: Op, path: String, err: std::io::Error },
+    /// The running CPU doesn't have a known set of events.
+    UnsupportedCpu { cpu: String },
+    /// Events for some microarchitecture were used on a different CPU.
+    CatalogMismatch { catalog: Uarch, cpu: String },
+    /// An event isn't defined for some microarchitecture.
+    UnknownEvent { name: String, uarch: Uarch },
+    /// A unit mask isn't valid for some event.
+    InvalidUnitMask { event: String, umask: u8 },
+    /// A file with event definitions couldn't be parsed.
+    InvalidEventFile { path: String, reason: String },
+    /// An event specification couldn't be parsed (see [crate::spec]).
+    InvalidSpec { spec: String, reason: String },
+    /// Some entry in a [crate::pmc::PerfCtlDescriptor] is invalid.
+    InvalidDescriptor { idx: usize, reason: String },
+    /// No counter can be assigned to some event (see [crate::mux]).
+    NoCounter { event: String },
+    /// A `PERF_CTL` value in hardware doesn't match the value written.
+    CtlMismatch { idx: usize, expected: u64, actual: u64 },
+    /// The calling thread isn't pinned to the core where the counters are
+    /// programmed.
+    AffinityMismatch { core: usize, cores: Vec<usize> },
+    /// Couldn't read the affinity of the calling thread.
+    GetAffinity { errno: Errno },
+    /// Some statement can't be assembled (see [crate::asm]).
+    InvalidAsm { line: usize, text: String, reason: String },
+    /// An experiment file couldn't be parsed (see [crate::experiment]).
+    InvalidExperiment { path: String, reason: String },
+    /// No counter is programmed with an interrupt event, which is needed
+    /// for [crate::irq::IrqGuard::Counters].
+    NoIrqCounter,
+}
+
+impl Error {
+    /// Return the errno associated with this error (if any).
+    pub fn errno(&self) -> Option<Errno> {
+        use Error::*;
+        match self {
+            Device { errno, .. } | Perf { errno, .. }
+                | Affinity { errno, .. } | GetAffinity { errno } => {
+                Some(*errno)
+            },
+            _ => None,
+        }
+    }
+
+    /// Return a hint about the most likely cause of an error (if any).
+    fn hint(&self) -> Option<&'static str> {
+        use Error::*;
+        match self {
+            Device { op: Op::Open, errno: Errno::ENOENT, .. } => {
+                Some("kernel module not loaded?")
+            },
+            Device { errno: Errno::EACCES, .. } => Some("permission denied?"),
+            Device { op: Op::ReadCtl, errno: Errno::EINVAL, .. }
+                | Device { op: Op::SetCpu, errno: Errno::EINVAL, .. } => {
+                Some("kernel module out of date, or invalid core?")
+            },
+            Device { op: Op::SetCpu, errno: Errno::EBUSY, .. } => {
+                Some("counters already enabled on that core?")
+            },
+            AffinityMismatch { .. } => Some("see util::pin_to_core"),
+            NoIrqCounter => Some("add ls_int_taken to the events?"),
+            Perf { op: Op::PerfEventOpen, errno } => match errno {
+                Errno::ENOENT | Errno::EINVAL => Some("event unsupported?"),
+                Errno::EACCES | Errno::EPERM => {
+                    Some("check /proc/sys/kernel/perf_event_paranoid")
+                },
+                Errno::EOPNOTSUPP => Some("no PMU available?"),
+                _ => None,
+            },
+            Affinity { errno: Errno::EINVAL, .. } => Some("invalid core?"),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Error::*;
+        match self {
+            Device { op, path, errno } => {
+                write!(f, "{} on {} failed: {}", op.to_str(), path, errno)?
+            },
+            Perf { op, errno } => {
+                write!(f, "{} on perf event failed: {}", op.to_str(), errno)?
+            },
+            Affinity { core, errno } => {
+                write!(f, "couldn't pin to core {}: {}", core, errno)?
+            },
+            Codegen { op, reason } => {
+                write!(f, "couldn't {}: {}", op.to_str(), reason)?
+            },
+            Unsupported { op, reason } => {
+                write!(f, "{} unsupported: {}", op.to_str(), reason)?
+            },
+            Io { op, path, err } => {
+                write!(f, "{} {} failed: {}", op.to_str(), path, err)?
+            },
+            UnsupportedCpu { cpu } => {
+                write!(f, "no known events for this CPU ({})", cpu)?
+            },
+            CatalogMismatch { catalog, cpu } => {
+                write!(f, "using {} events on a {} CPU", catalog, cpu)?
+            },
+            UnknownEvent { name, uarch } => {
+                write!(f, "event '{}' isn't defined for {}", name, uarch)?
+            },
+            InvalidUnitMask { event, umask } => {
+                write!(f, "invalid unit mask {:#04x} for '{}'", umask, event)?
+            },
+            InvalidEventFile { path, reason } => {
+                write!(f, "invalid event file {}: {}", path, reason)?
+            },
+            InvalidSpec { spec, reason } => {
+                write!(f, "invalid event '{}': {}", spec, reason)?
+            },
+            InvalidDescriptor { idx, reason } => {
+                write!(f, "invalid entry for counter {}: {}", idx, reason)?
+            },
+            NoCounter { event } => {
+                write!(f, "no counter can be used for '{}'", event)?
+            },
+            CtlMismatch { idx, expected, actual } => {
+                write!(f, "PERF_CTL for counter {} is {:#x}, expected {:#x}",
+                    idx, actual, expected)?
+            },
+            AffinityMismatch { core, cores } => {
+                write!(f, "counters are on core {}, but this thread can run \
+                    on cores {:?}", core, cores)?
+            },
+            GetAffinity { errno } => {
+                write!(f, "{} failed: {}", Op::GetAffinity.to_str(), errno)?
+            },
+            InvalidAsm { line, text, reason } => {
+                write!(f, "invalid assembly on line {} ('{}'): {}", line,
+                    text, reason)?
+            },
+            InvalidExperiment { path, reason } => {
+                write!(f, "invalid experiment {}: {}", path, reason)?
+            },
+            NoIrqCounter => {
+                write!(f, "no counter is programmed with PMCx02B or PMCx02C")?
+            },
+        }
+        if let Some(hint) = self.hint() {
+            write!(f, " ({})", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
@@ -1,4 +1,8 @@
 //! PMC event definitions (for Zen 2).
+//!
+//! See [crate::catalog] for events on other microarchitectures.
+
+use crate::catalog::{ Catalog, ZEN2 };
 
 /// Some property that characterizes an event.
 pub enum EventProperty {
@@ -32,6 +36,47 @@ impl CounterUnit {
     }
 }
 
+/// Software events provided by the Linux `perf_event_open()` interface.
+///
+/// These are not PMC events, and can only be counted with 
+/// [crate::perf::PerfEventContext]. The discriminant for each variant is the 
+/// corresponding `PERF_COUNT_SW_*` value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SoftwareEvent {
+    CpuClock        = 0,
+    TaskClock       = 1,
+    PageFaults      = 2,
+    ContextSwitches = 3,
+    CpuMigrations   = 4,
+    PageFaultsMin   = 5,
+    PageFaultsMaj   = 6,
+}
+impl SoftwareEvent {
+    /// All software events.
+    pub const ALL: [SoftwareEvent; 7] = [
+        SoftwareEvent::CpuClock,
+        SoftwareEvent::TaskClock,
+        SoftwareEvent::PageFaults,
+        SoftwareEvent::ContextSwitches,
+        SoftwareEvent::CpuMigrations,
+        SoftwareEvent::PageFaultsMin,
+        SoftwareEvent::PageFaultsMaj,
+    ];
+
+    /// Return the name used for this event by the Linux `perf` tool.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SoftwareEvent::CpuClock => "cpu-clock",
+            SoftwareEvent::TaskClock => "task-clock",
+            SoftwareEvent::PageFaults => "page-faults",
+            SoftwareEvent::ContextSwitches => "context-switches",
+            SoftwareEvent::CpuMigrations => "cpu-migrations",
+            SoftwareEvent::PageFaultsMin => "minor-faults",
+            SoftwareEvent::PageFaultsMaj => "major-faults",
+        }
+    }
+}
+
 /// A description of an event.
 pub struct EventDesc {
     pub desc: &'static str,
@@ -54,6 +99,9 @@ pub enum Event {
     Undefined(u16, u8),
     Merge,
 
+    /// A Linux software event (only usable with `perf_event_open()`).
+    Software(SoftwareEvent),
+
     /// PMCx025 - "Retired Lock Instructions"
     LsLocks(u8),
     SpecLockHiSpec,
@@ -236,13 +284,57 @@ impl Event {
                 unit: Instruction(Retired),
             },
 
+            Software(e) => EventDesc {
+                desc: match e {
+                    SoftwareEvent::CpuClock => "CPU clock (ns)",
+                    SoftwareEvent::TaskClock => "Task clock (ns)",
+                    SoftwareEvent::PageFaults => "Page faults",
+                    SoftwareEvent::ContextSwitches => "Context switches",
+                    SoftwareEvent::CpuMigrations => "CPU migrations",
+                    SoftwareEvent::PageFaultsMin => "Minor page faults",
+                    SoftwareEvent::PageFaultsMaj => "Major page faults",
+                },
+                unit: UndefinedUnit,
+            },
+
             _ => EventDesc { 
-                desc: "No description provided",
+                desc: self.catalog().and_then(|c| c.desc_of(self))
+                    .unwrap_or("No description provided"),
                 unit: UndefinedUnit
             },
         }
     }
 
+    /// Return the name of this event from the Linux `perf` event tables
+    /// (i.e. `ls_dispatch.ld_dispatch`), if it's known.
+    ///
+    /// Raw events ([Event::Undefined]) can only be named when a catalog
+    /// has been installed (see [Catalog::install]).
+    pub fn name(&self) -> Option<String> {
+        self.catalog().and_then(|c| c.name_of(self))
+    }
+
+    /// Return the catalog used to name and describe this event.
+    fn catalog(&self) -> Option<&'static Catalog> {
+        match Catalog::installed() {
+            Some(cat) => Some(cat),
+            None if self.is_named() => Some(&ZEN2),
+            None => None,
+        }
+    }
+
+    /// Returns true if this is a Linux software event.
+    pub fn is_software(&self) -> bool {
+        matches!(self, Event::Software(_))
+    }
+
+    /// Returns true if this is a named (Zen 2) event, rather than a raw
+    /// event, merge event, or software event.
+    pub fn is_named(&self) -> bool {
+        !matches!(self,
+            Event::Undefined(..) | Event::Merge | Event::Software(_))
+    }
+
     /// Convert an [Event] and unit mask into a pair of integers.
     pub fn convert(&self) -> (u16, u8) {
         use Event::*;
@@ -250,6 +342,9 @@ impl Event {
             Undefined(e, m)               => (*e & 0xfff, *m),
             Merge                         => (0xfff, 0x00),
 
+            // Software events have no PERF_CTL encoding
+            Software(_)                   => (0x000, 0x00),
+
             LsLocks(m)                    => (0x0025, *m),
             SpecLockHiSpec                => (0x0025, 0x08),
             SpecLockLoSpec                => (0x0025, 0x04),
@@ -0,0 +1,559 @@
+//! Declarative experiments, described in TOML files.
+//!
+//! An [Experiment] lists the body of a test (as assembly text, see
+//! [crate::asm]), a set of events, and how the test is run. Each point in
+//! a parameter sweep is measured with a [PMCTest] (multiplexing events when
+//! there aren't enough counters, see [crate::mux]), and the results are
+//! collected into serializable [ExperimentResults].
+//!
+//! ```toml
+//! name = "dependent adds"
+//! events = ["ex_ret_instr", "ls_not_halted_cyc", "r0c0,cmask=1",
+//!     "ls_int_taken"]
+//! iters = 4096
+//! warmup = 256
+//! floor = true
+//! discard = "counters"
+//!
+//! [body]
+//! asm = """
+//!     add rax, {imm}
+//!     add rbx, rax
+//! """
+//! repeat = 4
+//!
+//! [[sweep]]
+//! param = "imm"
+//! values = [1, 0x100, 0x10000]
+//!
+//! [[sweep]]
+//! param = "repeat"
+//! range = [1, 16]
+//! step = 5
+//! ```
+//!
+//! - `events` are event specifications (see [crate::spec])
+//! - `iters` is the number of measured iterations for each group of events
+//!   (4096 by default), and `warmup` is the number of iterations run (and
+//!   discarded) before measuring each group
+//! - `floor` measures the overhead with an empty test
+//!   (see [PMCTest::with_floor])
+//! - `discard` is either `counters` or `proc_interrupts` (see
+//!   [crate::irq::IrqGuard]). With `counters`, the events must include an
+//!   interrupt event (i.e. `ls_int_taken`) and fit in a single group.
+//! - The body is given either as `asm`, or as a `file` (relative to the
+//!   experiment file), and is repeated `repeat` times (1 by default)
+//! - Each `sweep` parameter takes a list of `values`, or an inclusive
+//!   `range` with an optional `step`. The parameter `repeat` overrides the
+//!   number of times the body is repeated, and any other parameter `x` is
+//!   substituted for `{x}` in the body. When there are several parameters,
+//!   every combination of values is measured.
+//!
+//! ```no_run
+//! use lamina::experiment::Experiment;
+//!
+//! fn main() -> Result<(), lamina::Error> {
+//!     lamina::util::pin_to_core(0)?;
+//!     let mut ctx = lamina::ctx::open_backend()?;
+//!     let exp = Experiment::read("experiments/add_chain.toml")?;
+//!     let res = exp.run(ctx.as_mut())?;
+//!     println!("{}", res.to_json());
+//!     Ok(())
+//! }
+//! ```
+//!
+
+use std::collections::BTreeMap;
+use std::path::{ Path, PathBuf };
+use serde::{ Serialize, Deserialize };
+
+use crate::PMCTest;
+use crate::asm::Block;
+use crate::builder::TestBuilder;
+use crate::ctx::PMCBackend;
+use crate::event::Event;
+use crate::irq::IrqGuard;
+use crate::mux::{ EventResult, Multiplexer, MuxResults };
+use crate::pmc::{ PerfCtl, PerfCtlDescriptor };
+use crate::spec;
+use crate::stats::{ Samples, Summary };
+use crate::error::{ Error, Op };
+
+type Err<T> = Result<T, Error>;
+
+/// The name of the parameter which overrides [Body::repeat].
+pub const REPEAT: &str = "repeat";
+
+fn default_iters() -> usize { 0x1000 }
+fn default_repeat() -> usize { 1 }
+fn default_step() -> i64 { 1 }
+
+/// A test, the events measured while running it, and a parameter sweep.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Experiment {
+    pub name: String,
+    /// Event specifications (see [crate::spec]).
+    pub events: Vec<String>,
+    pub body: Body,
+    /// The number of measured iterations for each group of events.
+    #[serde(default = "default_iters")]
+    pub iters: usize,
+    /// The number of iterations run before measuring each group of events.
+    #[serde(default)]
+    pub warmup: usize,
+    /// Measure the overhead with an empty test (see [PMCTest::with_floor]).
+    #[serde(default)]
+    pub floor: bool,
+    /// Discard iterations perturbed by interrupts (see
+    /// [PMCTest::discard_interrupts]).
+    #[serde(default)]
+    pub discard: Option<IrqGuard>,
+    /// Parameters to sweep over (the first parameter is outermost).
+    #[serde(default)]
+    pub sweep: Vec<Param>,
+}
+
+/// The body of the test in an [Experiment].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Body {
+    /// Instructions in Intel syntax (see [crate::asm]). When the body is
+    /// given as a file, this holds the contents of the file.
+    pub asm: Option<String>,
+    /// A file with instructions (relative to the experiment file).
+    pub file: Option<PathBuf>,
+    /// The number of times the body is repeated.
+    #[serde(default = "default_repeat")]
+    pub repeat: usize,
+}
+
+/// A parameter in a sweep.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Param {
+    /// Either [REPEAT], or the name of a placeholder in the body.
+    pub param: String,
+    /// A list of values.
+    pub values: Option<Vec<i64>>,
+    /// An inclusive range of values (see [Param::step]).
+    pub range: Option<(i64, i64)>,
+    /// The distance between values in [Param::range].
+    #[serde(default = "default_step")]
+    pub step: i64,
+}
+impl Param {
+    /// Return each value of this parameter.
+    pub fn values(&self) -> Vec<i64> {
+        match (&self.values, self.range) {
+            (Some(values), _) => values.clone(),
+            (None, Some((lo, hi))) => {
+                (lo..=hi).step_by(self.step.max(1) as usize).collect()
+            },
+            (None, None) => Vec::new(),
+        }
+    }
+}
+
+/// The value of each parameter at some point in a sweep (in the same order
+/// as [Experiment::sweep]).
+pub type Point = Vec<(String, i64)>;
+
+impl Experiment {
+    /// Parse an experiment. A body given as a file is read relative to the
+    /// current directory.
+    ///
+    /// ```
+    /// use lamina::experiment::Experiment;
+    /// let exp = Experiment::parse(r#"
+    ///     name = "shifts"
+    ///     events = ["r0c0"]
+    ///     body = { asm = "shl rax, {n}", repeat = 2 }
+    ///     sweep = [
+    ///         { param = "n", values = [1, 4] },
+    ///         { param = "repeat", range = [8, 24], step = 8 },
+    ///     ]
+    /// "#).unwrap();
+    /// let points = exp.points();
+    /// assert_eq!(points.len(), 6);
+    /// let (n, repeat) = (&points[1][0], &points[1][1]);
+    /// assert_eq!((n.1, repeat.1), (1, 16));
+    ///
+    /// let (block, repeat) = exp.body(&points[5]).unwrap();
+    /// assert_eq!(block.bytes, [0x48, 0xc1, 0xe0, 0x04]);
+    /// assert_eq!(repeat, 24);
+    /// ```
+    pub fn parse(text: &str) -> Err<Self> {
+        Self::load(text, None)
+    }
+
+    /// Read an experiment from some file.
+    pub fn read(path: impl AsRef<Path>) -> Err<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|err| Error::Io {
+            op: Op::ReadFile, path: path.display().to_string(), err
+        })?;
+        Self::load(&text, Some(path))
+    }
+
+    fn load(text: &str, path: Option<&Path>) -> Err<Self> {
+        let err = |reason: String| Error::InvalidExperiment {
+            path: path.map_or("<string>".to_string(), |p| p.display()
+                .to_string()),
+            reason,
+        };
+        let mut exp: Self = toml::from_str(text)
+            .map_err(|e| err(e.to_string().trim_end().to_string()))?;
+
+        if exp.events.is_empty() {
+            return Err(err("no events".to_string()));
+        }
+        match (&exp.body.asm, &exp.body.file) {
+            (Some(_), None) => {},
+            (None, Some(file)) => {
+                let dir = path.and_then(|p| p.parent())
+                    .unwrap_or_else(|| Path::new(""));
+                let file = dir.join(file);
+                let text = std::fs::read_to_string(&file)
+                    .map_err(|err| Error::Io {
+                        op: Op::ReadFile, path: file.display().to_string(), err
+                    })?;
+                exp.body.asm = Some(text);
+            },
+            _ => {
+                return Err(err("the body needs either 'asm' or 'file'"
+                    .to_string()));
+            },
+        }
+
+        let asm = exp.body.asm.as_deref().unwrap_or("");
+        for (idx, p) in exp.sweep.iter().enumerate() {
+            if p.values.is_some() == p.range.is_some() {
+                return Err(err(format!("parameter '{}' needs either \
+                    'values' or 'range'", p.param)));
+            }
+            if p.step <= 0 {
+                return Err(err(format!("parameter '{}' has a step of {}",
+                    p.param, p.step)));
+            }
+            match (&p.values, p.range) {
+                (_, Some((lo, hi))) if lo > hi => {
+                    return Err(err(format!("parameter '{}' has an empty \
+                        range {}..={}", p.param, lo, hi)));
+                },
+                (Some(values), _) if values.is_empty() => {
+                    return Err(err(format!("parameter '{}' has no values",
+                        p.param)));
+                },
+                _ => {},
+            }
+            if exp.sweep[..idx].iter().any(|x| x.param == p.param) {
+                return Err(err(format!("parameter '{}' is swept twice",
+                    p.param)));
+            }
+            if p.param == REPEAT {
+                if p.values().iter().any(|v| *v < 0) {
+                    return Err(err("negative value for 'repeat'"
+                        .to_string()));
+                }
+            } else if !asm.contains(&format!("{{{}}}", p.param)) {
+                return Err(err(format!("parameter '{}' isn't used in the \
+                    body", p.param)));
+            }
+        }
+        Ok(exp)
+    }
+
+    /// Return every point in the sweep (a single point when there are no
+    /// parameters).
+    pub fn points(&self) -> Vec<Point> {
+        let mut points: Vec<Point> = vec![Vec::new()];
+        for p in self.sweep.iter() {
+            let values = p.values();
+            points = points.iter()
+                .flat_map(|point| values.iter().map(move |val| {
+                    let mut point = point.clone();
+                    point.push((p.param.clone(), *val));
+                    point
+                }))
+                .collect();
+        }
+        points
+    }
+
+    /// Assemble the body at some point in the sweep, returning the
+    /// instructions and the number of times they're repeated.
+    pub fn body(&self, point: &[(String, i64)]) -> Err<(Block, usize)> {
+        let mut text = self.body.asm.clone().unwrap_or_default();
+        let mut repeat = self.body.repeat;
+        for (name, val) in point.iter() {
+            if name == REPEAT {
+                repeat = *val as usize;
+            } else {
+                text = text.replace(&format!("{{{}}}", name),
+                    &val.to_string());
+            }
+        }
+        Ok((Block::assemble(&text)?, repeat))
+    }
+
+    /// Parse the events, returning each event and its `PERF_CTL` value.
+    pub fn ctls(&self) -> Err<(Vec<Event>, Vec<PerfCtl>)> {
+        spec::parse_events(&self.events)
+    }
+
+    /// Check that the events can be parsed, and that the body can be
+    /// assembled at every point in the sweep (without running anything).
+    ///
+    /// ```
+    /// use lamina::experiment::Experiment;
+    /// let text = r#"
+    ///     name = "discard"
+    ///     events = ["r0c0"]
+    ///     discard = "counters"
+    ///     body = { asm = "nop" }
+    /// "#;
+    /// let exp = Experiment::parse(text).unwrap();
+    /// assert!(exp.check().is_err());
+    ///
+    /// let text = text.replace(r#"["r0c0"]"#, r#"["r0c0", "r02c"]"#);
+    /// let exp = Experiment::parse(&text).unwrap();
+    /// assert!(exp.check().is_ok());
+    ///
+    /// // Ranges can't be empty
+    /// let text = format!("{}{}", text, r#"
+    ///     sweep = [{ param = "repeat", range = [8, 1] }]
+    /// "#);
+    /// assert!(Experiment::parse(&text).is_err());
+    /// ```
+    pub fn check(&self) -> Err<()> {
+        let (events, ctls) = self.ctls()?;
+        self.check_discard(&events, &ctls)?;
+        for point in self.points() {
+            self.body(&point)?;
+        }
+        Ok(())
+    }
+
+    /// With [IrqGuard::Counters], check that some event counts interrupts,
+    /// and that the events fit in a single group (since events aren't
+    /// repeated in each group, see [Multiplexer]).
+    fn check_discard(&self, events: &[Event], ctls: &[PerfCtl]) -> Err<()> {
+        if self.discard != Some(IrqGuard::Counters) {
+            return Ok(());
+        }
+        let err = |reason: String| Error::InvalidExperiment {
+            path: self.name.clone(), reason
+        };
+        if !events.iter().any(IrqGuard::is_irq_event) {
+            return Err(err("discarding with counters needs an interrupt \
+                event (i.e. 'ls_int_taken')".to_string()));
+        }
+        let mux = Multiplexer::from_ctls(events, ctls, [true; 6])?;
+        if mux.groups.len() > 1 {
+            return Err(err(format!("discarding with counters needs every \
+                event in a single group (not {})", mux.groups.len())));
+        }
+        Ok(())
+    }
+
+    /// Run the test at every point in the sweep.
+    ///
+    /// Everything is assembled before running, so that errors are reported
+    /// before measuring anything.
+    pub fn run(&self, ctx: &mut dyn PMCBackend) -> Err<ExperimentResults> {
+        let (events, ctls) = self.ctls()?;
+        self.check_discard(&events, &ctls)?;
+        let bodies = self.points().into_iter()
+            .map(|point| Ok((self.body(&point)?, point)))
+            .collect::<Err<Vec<_>>>()?;
+
+        let mut points = Vec::new();
+        for ((block, repeat), params) in bodies {
+            let code = TestBuilder::pmc()?
+                .prologue()
+                .start()
+                .repeat(repeat, |asm| block.emit(asm))
+                .stop()
+                .epilogue()
+                .finish()?;
+            let mut test = PMCTest::from_compiled("experiment", &code,
+                &PerfCtlDescriptor::new());
+            if self.floor {
+                test = test.with_floor()?;
+            }
+            if let Some(guard) = self.discard {
+                test = test.discard_interrupts(guard);
+            }
+            let mux = Multiplexer::from_ctls(&events, &ctls,
+                test.source.counters())?
+                .warmup(self.warmup);
+            let res = mux.run(ctx, &mut test, self.iters)?;
+            points.push(PointRecord::new(params, repeat, &res));
+        }
+        Ok(ExperimentResults {
+            name: self.name.clone(),
+            iters: self.iters,
+            warmup: self.warmup,
+            points,
+        })
+    }
+}
+
+/// Results for a single event (i.e. at some point in a sweep).
+#[derive(Clone, Debug, Serialize)]
+pub struct EventRecord {
+    /// The name of the event.
+    pub event: String,
+    /// The event select code.
+    pub select: u16,
+    /// The unit mask.
+    pub umask: u8,
+    /// The raw `PERF_CTL` value.
+    pub ctl: u64,
+    /// The group that this event was counted in (see [crate::mux]).
+    pub group: usize,
+    /// The counter used for this event.
+    pub counter: usize,
+    /// Summary statistics (if there are any samples).
+    pub summary: Option<Summary>,
+    /// The median value from the floor (if any).
+    pub overhead: Option<f64>,
+    /// Per-iteration samples.
+    pub samples: Vec<usize>,
+    /// Per-iteration samples from the floor (if any).
+    pub floor: Option<Vec<usize>>,
+}
+
+/// Results for a single point in a sweep.
+#[derive(Clone, Debug, Serialize)]
+pub struct PointRecord {
+    /// The value of each parameter (other than [REPEAT]).
+    pub params: BTreeMap<String, i64>,
+    /// The number of times the body was repeated.
+    pub repeat: usize,
+    pub events: Vec<EventRecord>,
+}
+impl EventRecord {
+    /// Collect the results for a single event (see [crate::mux]).
+    pub fn new(r: &EventResult) -> Self {
+        let (select, umask) = r.event.convert();
+        Self {
+            event: r.event.to_string(),
+            select,
+            umask,
+            ctl: r.ctl.0 as u64,
+            group: r.group,
+            counter: r.counter,
+            summary: r.summary(),
+            overhead: r.floor.as_ref().filter(|f| !f.is_empty())
+                .map(|f| f.median()),
+            samples: r.data.clone(),
+            floor: r.floor.clone(),
+        }
+    }
+}
+
+impl PointRecord {
+    fn new(params: Point, repeat: usize, res: &MuxResults) -> Self {
+        let events = res.events.iter().map(EventRecord::new).collect();
+        let params = params.into_iter()
+            .filter(|(name, _)| name != REPEAT)
+            .collect();
+        Self { params, repeat, events }
+    }
+
+    /// Return the parameters formatted as `name=value` pairs.
+    fn params_str(&self, sep: &str) -> String {
+        self.params.iter()
+            .map(|(name, val)| format!("{}={}", name, val))
+            .collect::<Vec<_>>()
+            .join(sep)
+    }
+}
+
+/// A single row in CSV output.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    experiment: &'a str,
+    params: String,
+    repeat: usize,
+    event: &'a str,
+    group: usize,
+    counter: usize,
+    ctl: String,
+    kind: &'a str,
+    sample: usize,
+    value: usize,
+}
+
+/// Results from running an [Experiment].
+#[derive(Clone, Debug, Serialize)]
+pub struct ExperimentResults {
+    /// The name of the experiment.
+    pub name: String,
+    /// The number of measured iterations for each group of events.
+    pub iters: usize,
+    /// The number of iterations run before measuring each group of events.
+    pub warmup: usize,
+    /// Results for each point in the sweep (in order).
+    pub points: Vec<PointRecord>,
+}
+impl ExperimentResults {
+    /// Serialize these results to a JSON string.
+    pub fn to_json(&self) -> String {
+        // Non-finite values in a summary are written as null
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Write these results as CSV to some [std::io::Write], with one row
+    /// for each sample (see [crate::export] for the meaning of `kind`).
+    pub fn write_csv_to(&self, w: impl std::io::Write)
+        -> Result<(), csv::Error>
+    {
+        let mut wtr = csv::Writer::from_writer(w);
+        for point in self.points.iter() {
+            let params = point.params_str(";");
+            for r in point.events.iter() {
+                let floor = r.floor.iter().flatten().map(|v| ("floor", v));
+                let data = r.samples.iter().map(|v| ("data", v));
+                for (sample, (kind, value)) in data.enumerate()
+                    .chain(floor.enumerate())
+                {
+                    wtr.serialize(CsvRow {
+                        experiment: &self.name,
+                        params: params.clone(),
+                        repeat: point.repeat,
+                        event: &r.event,
+                        group: r.group,
+                        counter: r.counter,
+                        ctl: format!("0x{:016x}", r.ctl),
+                        kind,
+                        sample,
+                        value: *value,
+                    })?;
+                }
+            }
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        println!("# Experiment '{}' ({} points, {} iterations)", self.name,
+            self.points.len(), self.iters);
+        for point in self.points.iter() {
+            println!("## repeat={} {}", point.repeat, point.params_str(" "));
+            for r in point.events.iter() {
+                println!("|  PMCx{:03x} [{}] (group {}, counter {})",
+                    r.select, r.event, r.group, r.counter);
+                if let Some(s) = r.summary {
+                    println!("|   {}", s);
+                }
+                if let Some(overhead) = r.overhead {
+                    println!("|   Overhead:  {:.3}", overhead);
+                }
+            }
+        }
+    }
+}
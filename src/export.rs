@@ -0,0 +1,187 @@
+//! Exporting results in formats that can be consumed by other tools.
+//!
+//! A [TestRecord] is a serializable snapshot of the results from a
+//! [PMCTest], which can be written as JSON or CSV:
+//!
+//! - JSON: a single object, with one entry in `counters` for each event.
+//! - CSV: one row for each sample, with the columns
+//!   `test,iterations,counter,event,select,umask,ctl,kind,sample,value`.
+//!   `kind` is either `data` or `floor` (see [PMCTest::with_floor]).
+//!
+//! ```no_run
+//! # use lamina::PMCTest;
+//! # fn run(test: &PMCTest) -> Result<(), lamina::Error> {
+//! test.write_json("/tmp/results.json")?;
+//! test.write_csv("/tmp/results.csv")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::fs::File;
+use std::path::Path;
+use serde::{ Serialize, Deserialize };
+
+use crate::{ PMCTest, PMCResults };
+use crate::error::{ Error, Op };
+
+type Err<T> = Result<T, Error>;
+
+/// Results for a single counter.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterRecord {
+    /// The counter index.
+    pub counter: usize,
+    /// The name of the event.
+    pub event: String,
+    /// Description of the event.
+    pub desc: String,
+    /// The event select code.
+    pub select: u16,
+    /// The unit mask.
+    pub umask: u8,
+    /// The raw `PERF_CTL` value (if any).
+    pub ctl: Option<u64>,
+    /// Per-iteration samples.
+    pub samples: Vec<usize>,
+    /// Per-iteration samples from the floor (if any).
+    pub floor: Option<Vec<usize>>,
+}
+
+/// Results for a single test.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestRecord {
+    /// The name of the test.
+    pub name: String,
+    /// The number of iterations run.
+    pub iterations: usize,
+    /// The number of iterations discarded because of an interrupt.
+    #[serde(default)]
+    pub discarded: usize,
+    /// Results for each counter.
+    pub counters: Vec<CounterRecord>,
+}
+
+/// A single row in CSV output.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    test: &'a str,
+    iterations: usize,
+    counter: usize,
+    event: &'a str,
+    select: String,
+    umask: String,
+    ctl: String,
+    kind: &'a str,
+    sample: usize,
+    value: usize,
+}
+
+impl TestRecord {
+    /// Create a record from some set of results.
+    pub fn new(name: &str, res: &PMCResults) -> Self {
+        let mut counters = Vec::new();
+        for idx in 0..6 {
+            let (event, data) = match (&res.event[idx], &res.data[idx]) {
+                (Some(e), Some(d)) => (e, d),
+                _ => continue,
+            };
+            let (select, umask) = event.convert();
+            let floor = res.floor.as_ref().and_then(|f| f.data[idx].clone());
+            counters.push(CounterRecord {
+                counter: idx,
+                event: format!("{:?}", event),
+                desc: event.desc().desc.to_string(),
+                select,
+                umask,
+                ctl: res.ctl[idx].map(|ctl| ctl.0 as u64),
+                samples: data.clone(),
+                floor,
+            });
+        }
+        Self { 
+            name: name.to_string(), 
+            iterations: res.iters, 
+            discarded: res.discarded,
+            counters,
+        }
+    }
+
+    /// Serialize this record to a JSON string.
+    pub fn to_json(&self) -> String {
+        // This can't fail: there are no maps with non-string keys
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Write this record to a JSON file.
+    pub fn write_json(&self, path: impl AsRef<Path>) -> Err<()> {
+        let path = path.as_ref();
+        let f = File::create(path).map_err(|e| io_err(path, e))?;
+        serde_json::to_writer_pretty(f, self)
+            .map_err(|e| io_err(path, e.into()))
+    }
+
+    /// Write this record as CSV to some [std::io::Write].
+    pub fn write_csv_to(&self, w: impl std::io::Write)
+        -> Result<(), csv::Error>
+    {
+        let mut wtr = csv::Writer::from_writer(w);
+        for ctr in self.counters.iter() {
+            let (select, umask) = (
+                format!("0x{:03x}", ctr.select), format!("0x{:02x}", ctr.umask)
+            );
+            let ctl = ctr.ctl.map(|v| format!("0x{:016x}", v))
+                .unwrap_or_default();
+            let floor = ctr.floor.iter().flatten().map(|v| ("floor", v));
+            let data = ctr.samples.iter().map(|v| ("data", v));
+            for (sample, (kind, value)) in data.enumerate()
+                .chain(floor.enumerate())
+            {
+                wtr.serialize(CsvRow {
+                    test: &self.name,
+                    iterations: self.iterations,
+                    counter: ctr.counter,
+                    event: &ctr.event,
+                    select: select.clone(),
+                    umask: umask.clone(),
+                    ctl: ctl.clone(),
+                    kind,
+                    sample,
+                    value: *value,
+                })?;
+            }
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Write this record to a CSV file.
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> Err<()> {
+        let path = path.as_ref();
+        let f = File::create(path).map_err(|e| io_err(path, e))?;
+        self.write_csv_to(f).map_err(|e| io_err(path, e.into()))
+    }
+}
+
+/// Create an [Error] for a failed write to some file.
+pub(crate) fn io_err(path: &Path, err: std::io::Error) -> Error {
+    Error::Io { op: Op::WriteFile, path: path.display().to_string(), err }
+}
+
+impl PMCTest {
+    /// Return a serializable snapshot of the latest results.
+    pub fn record(&self) -> TestRecord {
+        TestRecord::new(self.name, &self.res)
+    }
+
+    /// Write the latest results to a JSON file.
+    pub fn write_json(&self, path: impl AsRef<Path>) -> Err<()> {
+        self.record().write_json(path)
+    }
+
+    /// Write the latest results to a CSV file.
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> Err<()> {
+        self.record().write_csv(path)
+    }
+}
+
@@ -0,0 +1,169 @@
+//! Detecting samples perturbed by interrupts.
+//!
+//! An interrupt (or SMI) which lands inside the measured region of a test
+//! usually adds a large number of unrelated events to each counter. When a
+//! [PMCTest] has an [IrqGuard] (see [PMCTest::discard_interrupts]), any
+//! iteration where an interrupt was detected is dropped from the results,
+//! and counted in [crate::PMCResults::discarded].
+//!
+//! ```no_run
+//! use lamina::*;
+//! use lamina::irq::IrqGuard;
+//! use lamina::pmc::PerfCtlDescriptor;
+//! use lamina::event::Event;
+//!
+//! fn main() -> Result<(), lamina::Error> {
+//!     lamina::util::pin_to_core(0)?;
+//!     let mut ctx = lamina::ctx::open_backend()?;
+//!
+//!     // Reserve the last counter for interrupts taken
+//!     let pmc = PerfCtlDescriptor::new()
+//!         .set(0, Event::ExRetInstr(0x00))
+//!         .set(5, Event::LsIntTaken(0x00));
+//!     ctx.write(&pmc)?;
+//!
+//!     let code = emit_rdpmc_test_all!(; nop ; nop)?;
+//!     let mut test = PMCTest::new("2 nops", &code, &pmc)
+//!         .discard_interrupts(IrqGuard::Counters);
+//!     test.run_iter(ctx.as_ref(), 0x1000)?;
+//!     println!("discarded {} samples", test.res.discarded);
+//!     Ok(())
+//! }
+//! ```
+//!
+
+use serde::{ Serialize, Deserialize };
+
+use crate::PMCTest;
+use crate::event::Event;
+use crate::error::{ Error, Op };
+
+type Err<T> = Result<T, Error>;
+
+/// A strategy for detecting interrupts during each iteration of a test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IrqGuard {
+    /// Use the counters programmed with interrupt events (see
+    /// [IrqGuard::is_irq_event]). A sample is discarded when any of these
+    /// counters is nonzero.
+    ///
+    /// This only detects interrupts inside the measured region, but it
+    /// uses up a counter. Running a test without any of these counters is
+    /// an error.
+    Counters,
+    /// Compare the number of interrupts in `/proc/interrupts` (on the cores
+    /// that the current thread can run on) before and after each iteration.
+    ///
+    /// This doesn't use a counter, but an iteration is also discarded when
+    /// an interrupt lands outside of the measured region, and SMIs aren't
+    /// detected at all.
+    ProcInterrupts,
+}
+impl IrqGuard {
+    /// Path to the per-core interrupt counts.
+    pub const PROC_INTERRUPTS: &'static str = "/proc/interrupts";
+
+    /// Returns true if some event counts interrupts or SMIs.
+    pub fn is_irq_event(e: &Event) -> bool {
+        // PMCx02B (SMIs received) and PMCx02C (interrupts taken)
+        !e.is_software() && matches!(e.convert().0, 0x02b | 0x02c)
+    }
+
+    /// Returns true if some sample was perturbed, according to the counters
+    /// programmed with interrupt events.
+    pub fn perturbed(events: &[Option<Event>; 6], values: &[usize; 6])
+        -> bool
+    {
+        events.iter().zip(values.iter()).any(|(e, val)| {
+            e.as_ref().is_some_and(Self::is_irq_event) && *val != 0
+        })
+    }
+}
+
+/// Return the total number of interrupts on the cores that the current
+/// thread can run on (see [IrqGuard::ProcInterrupts]).
+pub fn interrupts() -> Err<u64> {
+    let path = IrqGuard::PROC_INTERRUPTS;
+    let text = std::fs::read_to_string(path).map_err(|err| Error::Io {
+        op: Op::ReadFile, path: path.to_string(), err
+    })?;
+    Ok(parse_interrupts(&text, &crate::util::affinity()?))
+}
+
+/// Sum the number of interrupts on some set of cores, given the contents of
+/// `/proc/interrupts`.
+///
+/// The first line names a column for each online CPU (i.e. `CPU0`), and
+/// each of the following lines has a count for each column. Lines without
+/// a count for every column (i.e. `ERR`) are ignored.
+///
+/// ```
+/// let text = "           CPU0       CPU2\n\
+///               0:         40          2   IO-APIC   2-edge      timer\n\
+///             LOC:       1000        500   Local timer interrupts\n\
+///             ERR:          3\n";
+/// assert_eq!(lamina::irq::parse_interrupts(text, &[2]), 502);
+/// assert_eq!(lamina::irq::parse_interrupts(text, &[0, 2]), 1542);
+/// ```
+pub fn parse_interrupts(text: &str, cores: &[usize]) -> u64 {
+    let mut lines = text.lines();
+    let header: Vec<&str> = match lines.next() {
+        Some(header) => header.split_whitespace().collect(),
+        None => return 0,
+    };
+    let columns: Vec<usize> = header.iter().enumerate()
+        .filter_map(|(col, name)| {
+            let cpu = name.strip_prefix("CPU")?.parse().ok()?;
+            if cores.contains(&cpu) { Some(col) } else { None }
+        })
+        .collect();
+    let mut total = 0;
+    for line in lines {
+        let counts: Vec<u64> = match line.split_once(':') {
+            Some((_, rest)) => rest.split_whitespace()
+                .map_while(|x| x.parse().ok())
+                .collect(),
+            None => continue,
+        };
+        if counts.len() < header.len() {
+            continue;
+        }
+        total += columns.iter().filter_map(|col| counts.get(*col))
+            .sum::<u64>();
+    }
+    total
+}
+
+impl PMCTest {
+    /// Discard any iteration of this test where an interrupt was detected
+    /// (see [IrqGuard]).
+    ///
+    /// When this test has a floor (see [PMCTest::with_floor]), samples from
+    /// the floor are also discarded.
+    ///
+    /// ```
+    /// use lamina::*;
+    /// use lamina::irq::IrqGuard;
+    /// use lamina::mock::{ MockContext, MockMode };
+    /// use lamina::pmc::PerfCtlDescriptor;
+    /// use lamina::event::Event;
+    ///
+    /// let ctx = MockContext::new(MockMode::Read);
+    /// let code = emit_rdpmc_test_all!(; nop).unwrap();
+    ///
+    /// // There's no counter for interrupts
+    /// let pmc = PerfCtlDescriptor::new().set(0, Event::ExRetInstr(0));
+    /// let mut test = PMCTest::new("nop", &code, &pmc)
+    ///     .discard_interrupts(IrqGuard::Counters);
+    /// assert!(matches!(test.run_iter(&ctx, 1), Err(Error::NoIrqCounter)));
+    ///
+    /// let pmc = pmc.set(5, Event::LsIntTaken(0));
+    /// test.reset(&pmc);
+    /// test.run_iter(&ctx, 1).unwrap();
+    /// ```
+    pub fn discard_interrupts(mut self, guard: IrqGuard) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+}
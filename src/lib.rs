@@ -21,6 +21,17 @@
 //! The script may not map cleanly to the situation on your machine, so you 
 //! should probably read it before running it.
 //!
+//! ## Counter backends
+//!
+//! Interactions with the PMCs go through the [ctx::PMCBackend] trait.
+//! [ctx::PMCContext] uses the kernel module, and [perf::PerfEventContext] 
+//! uses the Linux `perf_event_open()` interface (which works on stock 
+//! kernels, and also supports software events like task-clock). You can use
+//! [ctx::open_backend] to pick whichever one is available.
+//!
+//! [mock::MockContext] is a simulated backend which produces scripted counter
+//! values, for testing code that processes results without any hardware.
+//!
 //! ## Usage (simple tests without PMCs)
 //!
 //! For running code that doesn't rely on the kernel module and RDPMC usage,
@@ -30,34 +41,35 @@
 //!
 //! ## Usage (tests that issue RDPMC)
 //!
-//! ```
+//! ```no_run
 //! use lamina::*;
-//! use lamina::ctx::PMCContext;
+//! use lamina::ctx::{ PMCContext, PMCBackend };
 //! use lamina::pmc::PerfCtlDescriptor;
 //! use lamina::event::Event;
 //!
-//! fn main() -> Result<(), &'static str> {
-//!     // The kernel module always instruments PMCs on core 0
-//!     lamina::util::pin_to_core(0);
+//! fn main() -> Result<(), lamina::Error> {
+//!     // The kernel module instruments PMCs on the core given here, and
+//!     // RDPMC must run on the same core
+//!     lamina::util::pin_to_core(2)?;
 //!
 //!     // This is the interface to the kernel module 
-//!     let mut ctx = PMCContext::new()?;
+//!     let mut ctx = PMCContext::on_core(2)?;
 //!
 //!     // Measure retired instructions with counter 0.
-//!     let mut pmc = PerfCtlDescriptor::new()
-//!         .set(0, Event::ExRetInst(0), true);
+//!     let pmc = PerfCtlDescriptor::new()
+//!         .set(0, Event::ExRetInstr(0));
 //!
 //!     // Create a test
 //!     let code = emit_rdpmc_test_all!(
 //!         ; nop ; nop ; nop; nop
-//!     );
+//!     )?;
 //!     let mut test = PMCTest::new("4 nops", &code, &pmc);
 //!
 //!     // Enable counters for the selected events
-//!     ctx.write(&pmc);
+//!     ctx.write(&pmc)?;
 //!
 //!     // Run the test and collect results
-//!     test.run_iter(0x1000);
+//!     test.run_iter(&ctx, 0x1000)?;
 //!
 //!     // Do some analysis on the results
 //!     // ...
@@ -68,20 +80,43 @@
 //!
 //! You can see more examples in `bin/pmc/` directory.
 //!
+//! ## Usage (composing tests at runtime)
+//!
+//! The `emit_*` macros are wrappers around [builder::TestBuilder], which
+//! you can use directly when a test needs to be parameterized at runtime
+//! (see the [builder] module).
+//!
 
 #[allow(unused_macros)]
 
 pub mod codegen;
+pub mod builder;
 pub mod util;
 pub mod chase;
 pub mod x86;
 pub mod pmc;
 pub mod event;
 pub mod ctx;
+pub mod perf;
+pub mod mock;
+pub mod error;
+pub mod stats;
+pub mod export;
+pub mod cpuid;
+pub mod catalog;
+pub mod spec;
+pub mod mux;
+pub mod irq;
+pub mod capacity;
+pub mod probe;
+pub mod asm;
+pub mod experiment;
+
+pub use error::Error;
 
 use std::fs::File;
 use std::io::Write;
-use std::iter::FromIterator;
+use std::path::Path;
 use std::collections::BTreeMap;
 
 pub use dynasmrt::{
@@ -96,19 +131,17 @@ pub use dynasmrt::{
 
 
 /// Function pointer to emitted code which takes a pointer to an array of
-/// [usize] used to hold the results.
-///
-/// FIXME: You should make the typing here more explicit to indicate that
-/// we're passing a pointer to `[usize; 6]`.
-pub type PMCTestFn = extern "C" fn(*mut usize);
+/// six [usize] used to hold the results.
+pub type PMCTestFn = extern "C" fn(*mut [usize; 6]);
 
 
 /// Indicates the strategy used to obtain result values from emitted code.
+#[derive(Clone, Copy)]
 pub enum PMCTestInterface {
     /// Emitted code stores 6 result values in some array. 
-    ByRef(extern "C" fn(*mut [usize; 6])),
+    ByRef(PMCTestFn),
     /// Emitted code returns a single result value in RAX.
-    ByVal(extern "C" fn() -> usize),
+    ByVal(SimpleTestFn),
 }
 
 /// A set of results collected from PMCs.
@@ -122,6 +155,23 @@ pub struct PMCResults {
     pub max: [usize; 6],
     pub map: [BTreeMap<usize, usize>; 6],
 
+    /// Results from a matching test with an empty body (if any), used to
+    /// correct for the overhead of measurement (see [PMCTest::with_floor]).
+    pub floor: Option<Box<PMCResults>>,
+
+    /// The `PERF_CTL` value used for each event.
+    pub ctl: [Option<pmc::PerfCtl>; 6],
+    /// The number of iterations run.
+    pub iters: usize,
+
+    /// The width of each counter in bits (see [cpuid::counter_width]).
+    pub width: u32,
+    /// The number of samples where each counter wrapped around. The values
+    /// for these samples are masked to the width of the counter.
+    pub wrapped: [usize; 6],
+    /// The number of iterations discarded because of an interrupt (see
+    /// [PMCTest::discard_interrupts]).
+    pub discarded: usize,
 }
 impl PMCResults {
     /// Create a new set of results.
@@ -144,46 +194,207 @@ impl PMCResults {
             min: [0; 6],
             max: [0; 6],
             map: maps,
+            floor: None,
+            ctl: desc.ctl,
+            iters: 0,
+            width: cpuid::counter_width(),
+            wrapped: [0; 6],
+            discarded: 0,
         };
-        for idx in 0..6 {
-            res.event[idx] = desc.events[idx];
-            if let Some(_) = desc.events[idx] {
-                res.data[idx] = Some(Vec::new());
+        res.event = desc.events;
+        for (idx, data) in res.data.iter_mut().enumerate() {
+            let merge = idx > 0 && pmc::is_merged(&desc.events, idx - 1);
+            if desc.events[idx].is_some() && !merge {
+                *data = Some(Vec::new());
             }
         }
         res
     }
 
+    /// Returns true if some counter is merged with the next counter. 
+    /// The combined value of both counters is recorded for this counter, 
+    /// and no data is recorded for the merge event.
+    ///
+    /// ```
+    /// use lamina::*;
+    /// use lamina::ctx::PMCBackend;
+    /// use lamina::mock::{ MockContext, MockMode };
+    /// use lamina::pmc::{ PerfCtlDescriptor, COUNTER_WIDTH };
+    /// use lamina::event::Event;
+    ///
+    /// let mut ctx = MockContext::new(MockMode::Read);
+    /// let pmc = PerfCtlDescriptor::new()
+    ///     .set(0, Event::Undefined(0x03, 0xff))
+    ///     .set(1, Event::Merge);
+    /// ctx.write(&pmc).unwrap();
+    ///
+    /// // The merge event counts the high bits
+    /// ctx.script(0, &[5]);
+    /// ctx.script(1, &[2]);
+    /// let code = emit_rdpmc_test_all!(; nop).unwrap();
+    /// let mut test = PMCTest::new("merged", &code, &pmc);
+    /// test.run_iter(&ctx, 1).unwrap();
+    /// assert!(test.res.is_merged(0));
+    /// assert_eq!(test.res.data[0], Some(vec![(2 << COUNTER_WIDTH) + 5]));
+    /// assert_eq!(test.res.data[1], None);
+    /// assert_eq!(test.res.wrapped, [0; 6]);
+    /// ```
+    pub fn is_merged(&self, idx: usize) -> bool {
+        pmc::is_merged(&self.event, idx)
+    }
+
+    /// Append a set of values (one for each counter) to the results.
+    ///
+    /// Each value is the difference between two reads of a counter (in a
+    /// 64-bit register). If a counter wrapped around in between, the bits
+    /// above the width of the counter are set, and the value is masked.
+    fn push(&mut self, values: &[usize; 6]) {
+        let mask = match self.width {
+            w if w >= usize::BITS => usize::MAX,
+            w => (1 << w) - 1,
+        };
+        let mut values = *values;
+        for idx in 0..6 {
+            // Unused counters (and merge events) hold arbitrary values
+            if self.data[idx].is_none() {
+                continue;
+            }
+            // The low counter in a merged pair is expected to wrap around
+            if self.is_merged(idx) {
+                values[idx] = (values[idx + 1] << pmc::COUNTER_WIDTH)
+                    .wrapping_add(values[idx]);
+            } else if values[idx] & !mask != 0 {
+                self.wrapped[idx] += 1;
+                values[idx] &= mask;
+            }
+        }
+        for (data, val) in self.data.iter_mut().zip(values.iter()) {
+            if let Some(data) = data {
+                data.push(*val);
+            }
+        }
+    }
+
+    /// Recompute the min, max, and histogram for each counter.
+    fn update(&mut self) {
+        use stats::Samples;
+        for idx in 0..6 {
+            if let Some(data) = &self.data[idx] {
+                if data.is_empty() {
+                    continue;
+                }
+                self.map[idx] = data.histogram();
+                self.min[idx] = *data.iter().min().unwrap();
+                self.max[idx] = *data.iter().max().unwrap();
+            }
+        }
+    }
+
+    /// Return the overhead of measurement for a particular counter (the
+    /// median value from the floor), if a floor was measured.
+    pub fn overhead(&self, idx: usize) -> Option<usize> {
+        assert!(idx < 6);
+        let floor = self.floor.as_ref()?;
+        floor.summary(idx).map(|s| s.median.round() as usize)
+    }
+
+    /// Return the data for a particular counter with the overhead of
+    /// measurement subtracted (saturating at zero), if a floor was measured.
+    pub fn corrected(&self, idx: usize) -> Option<Vec<usize>> {
+        let overhead = self.overhead(idx)?;
+        self.data[idx].as_ref().map(|data| {
+            data.iter().map(|x| x.saturating_sub(overhead)).collect()
+        })
+    }
+
+    /// Return summary statistics for a particular counter (if any).
+    pub fn summary(&self, idx: usize) -> Option<stats::Summary> {
+        assert!(idx < 6);
+        use stats::Samples;
+        match &self.data[idx] {
+            Some(data) if !data.is_empty() => Some(data.summary()),
+            _ => None,
+        }
+    }
+
+    /// Return the data for a particular counter with outliers removed
+    /// (see [stats::Samples::filter_outliers]).
+    pub fn filtered(&self, idx: usize, k: f64) -> Option<Vec<usize>> {
+        assert!(idx < 6);
+        use stats::Samples;
+        match &self.data[idx] {
+            Some(data) if !data.is_empty() => Some(data.filter_outliers(k)),
+            _ => None,
+        }
+    }
+
     pub fn print_ctr(&self, idx: usize) {
         assert!(idx < 6);
+        if idx > 0 && self.is_merged(idx - 1) {
+            println!("|  PMCxfff [Merge] (merged into counter {})", idx - 1);
+            return;
+        }
         if let Some(event) = &self.event[idx] {
-            let mut dist = Vec::from_iter(&self.map[idx]);
-            dist.sort_by(|&(_, a), &(_,b)| b.cmp(&a));
             let evt = format!("{:x?}", event);
             //println!("| --------------------------------------------------");
-            println!("|  PMCx{:03x} [{}]", event.convert().0, evt);
+            match event.name() {
+                Some(name) => println!("|  PMCx{:03x} [{}] {}",
+                    event.convert().0, evt, name),
+                None => println!("|  PMCx{:03x} [{}]", event.convert().0, evt),
+            }
             println!("|   Description:  {}", event.desc().desc);
+            if let Some(ctl) = self.ctl[idx] {
+                println!("|   PERF_CTL:     {:#014x} ({})", ctl.0, ctl);
+            }
+            if self.wrapped[idx] != 0 {
+                println!("|   Wrapped:      {} samples (masked to {} bits)",
+                    self.wrapped[idx], self.width);
+            }
             //println!("|   Counter type: {}", event.desc().unit.to_str());
-            println!("|   min={:<5} max={:<5} mode={:<5} | dist={:?}",
-                self.min[idx], self.max[idx], dist[0].0, self.map[idx]
-            );
+            if let Some(s) = self.summary(idx) {
+                println!("|   min={:<5} max={:<5} mode={:<5} | dist={:?}",
+                    self.min[idx], self.max[idx], s.mode, self.map[idx]
+                );
+                println!("|   med={:<5} mean={:.3} sd={:.3} ci95=[{:.3},{:.3}]",
+                    s.median, s.mean, s.stddev, s.ci95.0, s.ci95.1
+                );
+            }
+            if let Some(floor) = &self.floor {
+                if let Some(s) = floor.summary(idx) {
+                    println!("|   floor:     min={:<5} max={:<5} med={:<5} \
+                        | dist={:?}", s.min, s.max, s.median, floor.map[idx]
+                    );
+                }
+            }
+            if let Some(data) = self.corrected(idx) {
+                use stats::Samples;
+                if !data.is_empty() {
+                    let s = data.summary();
+                    println!("|   corrected: min={:<5} max={:<5} med={:<5} \
+                        mean={:.3}", s.min, s.max, s.median, s.mean
+                    );
+                }
+            }
         }
     }
 
-    /// Write raw result data to a text file
-    pub fn write_txt(&self, name: &'static str) {
-        let mut f = File::create(name).expect("cant create file");
+    /// Write raw result data to a text file.
+    ///
+    /// See [export] for formats that can be parsed reliably.
+    pub fn write_txt(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let err = |e| export::io_err(path, e);
+        let mut f = File::create(path).map_err(err)?;
         for idx in 0..6 {
-            if let Some(event) = &self.event[idx] {
-                let data = if let Some(data) = &self.data[idx] {
-                    format!("{:?}", data)
-                } else { unreachable!() };
-                let line = format!("PMCx{:03x} {}|{}\n",
+            let (event, data) = (&self.event[idx], &self.data[idx]);
+            if let (Some(event), Some(data)) = (event, data) {
+                let line = format!("PMCx{:03x} {}|{:?}\n",
                     event.convert().0, event.desc().desc, data
                 );
-                f.write(line.as_bytes()).unwrap();
+                f.write_all(line.as_bytes()).map_err(err)?;
             }
         }
+        Ok(())
     }
 
 }
@@ -197,12 +408,26 @@ pub struct PMCTest {
     /// Pointer to emitted code.
     pub ptr: *const u8,
     /// Function pointer for emitted code.
-    pub func: PMCTestFn,
+    pub func: PMCTestInterface,
+    /// The source of measured value(s).
+    pub source: builder::Source,
+    /// Stub called in place of RDPMC by emitted code (if any).
+    pub stub: Option<codegen::ReadStub>,
+    /// Matching test with an empty body (see [PMCTest::with_floor]).
+    pub floor: Option<builder::CompiledTest>,
+    /// Used to discard perturbed iterations (see 
+    /// [PMCTest::discard_interrupts]).
+    pub guard: Option<irq::IrqGuard>,
     /// The latest set of result data from this test.
     pub res: PMCResults,
 }
 impl PMCTest {
-    /// Create a new test.
+    /// Value indicating that a counter cannot be read with RDPMC in emitted
+    /// code (see [emit_rdpmc_test_all]).
+    pub const NO_RDPMC: usize = usize::MAX;
+
+    /// Create a new test from code which reads all six counters 
+    /// (see [emit_rdpmc_test_all]).
     pub fn new(name: &'static str, buf: &ExecutableBuffer, 
         desc: &pmc::PerfCtlDescriptor
     ) -> Self {
@@ -212,13 +437,152 @@ impl PMCTest {
                 name,
                 ptr,
                 size: buf.len(),
-                func: std::mem::transmute(ptr),
+                func: PMCTestInterface::ByRef(std::mem::transmute::<
+                    *const u8, PMCTestFn
+                >(ptr)),
+                source: builder::Source::Pmc([true; 6]),
+                stub: None,
+                floor: None,
+                guard: None,
                 res: PMCResults::new(desc),
             }
         }
     }
+
+    /// Create a new test from code which returns the value of a single 
+    /// counter `ctr` in RAX (see [emit_rdpmc_test_single]).
+    ///
+    /// `sel` is the RDPMC index (the value of ECX) used by the emitted code,
+    /// which may differ from `ctr` (see [ctx::PMCBackend::rdpmc_index]).
+    /// Only the event for `ctr` in the descriptor is recorded in the results.
+    pub fn new_single(name: &'static str, buf: &ExecutableBuffer, 
+        desc: &pmc::PerfCtlDescriptor, ctr: usize, sel: u32,
+    ) -> Self {
+        assert!(ctr < 6);
+        let mut ctrs = [false; 6];
+        ctrs[ctr] = true;
+        let ptr: *const u8 = buf.ptr(AssemblyOffset(0));
+        unsafe {
+            Self { 
+                name,
+                ptr,
+                size: buf.len(),
+                func: PMCTestInterface::ByVal(std::mem::transmute::<
+                    *const u8, SimpleTestFn
+                >(ptr)),
+                source: builder::Source::SinglePmc { ctr, sel },
+                stub: None,
+                floor: None,
+                guard: None,
+                res: PMCResults::new(&Self::mask_desc(desc, ctrs)),
+            }
+        }
+    }
+
+    /// Create a new test from a [builder::CompiledTest].
+    ///
+    /// Only the events for counters read by the test are recorded in the 
+    /// results.
+    pub fn from_compiled(name: &'static str, test: &builder::CompiledTest,
+        desc: &pmc::PerfCtlDescriptor
+    ) -> Self {
+        Self {
+            name,
+            ptr: test.ptr(),
+            size: test.len(),
+            func: test.interface(),
+            source: test.source,
+            stub: test.stub,
+            floor: None,
+            guard: None,
+            res: PMCResults::new(&Self::mask_desc(desc, test.counters())),
+        }
+    }
+
+    /// Set the stub called in place of RDPMC by emitted code.
+    ///
+    /// Tests created with [PMCTest::new] from code emitted with `stub(...)`
+    /// (see [emit_rdpmc_test_all]) must carry the same stub, so that a floor
+    /// built by [PMCTest::with_floor] reads the counters the same way.
+    pub fn read_stub(mut self, stub: codegen::ReadStub) -> Self {
+        self.stub = Some(stub);
+        self
+    }
+
+    /// Return a copy of some descriptor with only a subset of counters.
+    fn mask_desc(desc: &pmc::PerfCtlDescriptor, ctrs: [bool; 6]) 
+        -> pmc::PerfCtlDescriptor
+    {
+        let mut res = *desc;
+        for (idx, en) in ctrs.iter().enumerate() {
+            if !en {
+                res.events[idx] = None;
+                res.ctl[idx] = None;
+            }
+        }
+        res
+    }
+
+    /// Build a matching test with an empty body, which is run alongside
+    /// this test in order to measure the overhead of measurement.
+    ///
+    /// Results from the floor are kept in [PMCResults::floor], and 
+    /// [PMCResults::corrected] returns the data with the overhead removed.
+    ///
+    /// The floor reads the counters with the same stub as this test (see
+    /// [PMCTest::read_stub]), if any:
+    ///
+    /// ```
+    /// use lamina::*;
+    /// use lamina::ctx::PMCBackend;
+    /// use lamina::mock::{ MockContext, MockMode };
+    /// use lamina::pmc::PerfCtlDescriptor;
+    /// use lamina::event::Event;
+    ///
+    /// let mut ctx = MockContext::new(MockMode::Stub);
+    /// let pmc = PerfCtlDescriptor::new().set(0, Event::ExRetInstr(0));
+    /// ctx.write(&pmc).unwrap();
+    ///
+    /// // The floor is measured first in each iteration
+    /// ctx.script(0, &[1, 5, 2, 6, 1, 7]);
+    /// let code = emit_rdpmc_test_all!(stub(ctx.read_stub()), ; nop).unwrap();
+    /// let mut test = PMCTest::new("floor", &code, &pmc)
+    ///     .read_stub(ctx.read_stub())
+    ///     .with_floor().unwrap();
+    /// test.run_iter(&ctx, 3).unwrap();
+    ///
+    /// assert_eq!(test.res.overhead(0), Some(1));
+    /// assert_eq!(test.res.corrected(0), Some(vec![4, 5, 6]));
+    /// ```
+    pub fn with_floor(mut self) -> Result<Self, Error> {
+        self.floor = Some(builder::TestBuilder::empty(self.source, self.stub)?);
+        let desc = pmc::PerfCtlDescriptor { 
+            events: self.res.event, ctl: self.res.ctl 
+        };
+        self.res.floor = Some(Box::new(PMCResults::new(&desc)));
+        Ok(self)
+    }
+
+    /// Discard all results, and start collecting results for a different
+    /// set of events (i.e. after writing a new descriptor). 
+    pub fn reset(&mut self, desc: &pmc::PerfCtlDescriptor) {
+        self.res = PMCResults::new(
+            &Self::mask_desc(desc, self.source.counters())
+        );
+        if self.floor.is_some() {
+            let desc = pmc::PerfCtlDescriptor { 
+                events: self.res.event, ctl: self.res.ctl 
+            };
+            self.res.floor = Some(Box::new(PMCResults::new(&desc)));
+        }
+    }
+
     pub fn print(&self) {
-        println!("# Test '{}'", self.name);
+        match self.guard {
+            Some(_) => println!("# Test '{}' ({} of {} iterations discarded)",
+                self.name, self.res.discarded, self.res.iters),
+            None => println!("# Test '{}'", self.name),
+        }
         for (idx, e) in self.res.event.iter().enumerate() {
             if e.is_some() {
                 self.res.print_ctr(idx);
@@ -226,48 +590,130 @@ impl PMCTest {
         }
     }
 
+    /// Call into emitted code once, returning the value of each counter.
+    fn call(ctx: &dyn ctx::PMCBackend, func: PMCTestInterface, 
+        ptr: *const u8, size: usize, events: &[Option<event::Event>; 6],
+    ) -> Result<[usize; 6], Error> 
+    {
+        let mut res: [usize; 6] = [0; 6];
+        match func {
+            PMCTestInterface::ByRef(func) => {
+                // Emitted code expects the RDPMC index for each counter
+                for (idx, sel) in res.iter_mut().enumerate() {
+                    *sel = match ctx.rdpmc_index(idx) {
+                        Some(x) => x as usize,
+                        None => Self::NO_RDPMC,
+                    };
+                }
+
+                util::clflush(size, ptr as *const [u8; 64]);
+
+                let mut start: [Option<u64>; 6] = [None; 6];
+                for idx in 0..6 {
+                    if res[idx] == Self::NO_RDPMC && events[idx].is_some() {
+                        start[idx] = Some(ctx.read_ctr(idx)?);
+                    }
+                }
+                func(&mut res as *mut [usize; 6]);
+                for idx in 0..6 {
+                    if let Some(start) = start[idx] {
+                        res[idx] = ctx.read_ctr(idx)?
+                            .wrapping_sub(start) as usize;
+                    }
+                }
+            },
+            PMCTestInterface::ByVal(func) => {
+                util::clflush(size, ptr as *const [u8; 64]);
+                let val = func();
+                if let Some(idx) = events.iter().position(|e| e.is_some()) {
+                    res[idx] = val;
+                }
+            },
+        }
+        Ok(res)
+    }
+
     /// Run emitted code some number of times.
     ///
     /// # Safety
-    /// This assumes that emitted code takes a pointer to an array of
-    /// six 64-bit elements and fills it with the values of the six PMC
-    /// counters (this is implicit in the definition of [PMCTestFn]. 
+    /// This assumes that emitted code follows the convention given by
+    /// [PMCTestInterface]: either it takes a pointer to an array of six 
+    /// 64-bit elements and fills it with the values of the six PMC counters 
+    /// (see [emit_rdpmc_test_all]), or it returns the value of a single 
+    /// counter in RAX (see [emit_rdpmc_test_single]).
+    ///
+    /// When reading all six counters, counters which cannot be read with 
+    /// RDPMC (see [ctx::PMCBackend::rdpmc_index]) are read by the backend 
+    /// immediately before and after calling into emitted code.
+    ///
+    /// If the test has a floor (see [PMCTest::with_floor]), the floor is 
+    /// run once before each iteration.
     ///
-    /// Right now the only gadget satisfying this should be the 
-    /// [emit_rdpmc_test_all] macro.
+    /// If the test has an [irq::IrqGuard] (see 
+    /// [PMCTest::discard_interrupts]), iterations perturbed by an interrupt
+    /// are discarded.
     ///
     /// Also note that this evicts code from the i-cache on each iteration.
     ///
-    pub fn run_iter(&mut self, iter: usize) {
+    pub fn run_iter(&mut self, ctx: &dyn ctx::PMCBackend, iter: usize) 
+        -> Result<(), Error>
+    {
         let mut res_vec = vec![[0usize;6]; iter];
+        let mut floor_vec = vec![[0usize;6]; iter];
+        let mut perturbed = vec![false; iter];
+        let proc_guard = self.guard == Some(irq::IrqGuard::ProcInterrupts);
+        let counter_guard = self.guard == Some(irq::IrqGuard::Counters);
+        if counter_guard && !self.res.event.iter().flatten()
+            .any(irq::IrqGuard::is_irq_event)
+        {
+            return Err(Error::NoIrqCounter);
+        }
         for i in 0..iter { 
-            let mut res: [usize; 6] = [0; 6];
-            util::clflush(self.size, self.ptr as *const [u8; 64]);
-            (self.func)(res.as_mut_ptr());
-            res_vec[i] = res;
+            let before = if proc_guard { irq::interrupts()? } else { 0 };
+            // The floor is interleaved with the test, so that both are 
+            // measured under the same conditions
+            if let Some(floor) = &self.floor {
+                floor_vec[i] = Self::call(ctx, floor.interface(), 
+                    floor.ptr(), floor.len(), &self.res.event
+                )?;
+            }
+            res_vec[i] = Self::call(ctx, self.func, self.ptr, self.size, 
+                &self.res.event
+            )?;
+            if proc_guard {
+                perturbed[i] = irq::interrupts()? != before;
+            }
         }
 
-        for res in res_vec.iter() {
-            for idx in 0..6 {
-                if let Some(ref mut v) = self.res.data[idx] { 
-                    v.push(res[idx]);
-                }
+        // With counters for interrupts, the test and the floor are
+        // discarded separately
+        let discard = |events: &[Option<event::Event>; 6], 
+            res: &[usize; 6], i: usize| 
+        {
+            perturbed[i] 
+                || (counter_guard && irq::IrqGuard::perturbed(events, res))
+        };
+        for (i, res) in res_vec.iter().enumerate() {
+            if discard(&self.res.event, res, i) {
+                self.res.discarded += 1;
+            } else {
+                self.res.push(res);
             }
         }
-
-        for idx in 0..6 {
-            if let Some(ref mut data) = self.res.data[idx] {
-                let mut map: BTreeMap<usize, usize> = BTreeMap::new();
-                for value in data.iter() {
-                    *map.entry(*value).or_insert(0) += 1;
+        self.res.iters += iter;
+        self.res.update();
+        if let Some(floor) = &mut self.res.floor {
+            for (i, res) in floor_vec.iter().enumerate() {
+                if discard(&floor.event, res, i) {
+                    floor.discarded += 1;
+                } else {
+                    floor.push(res);
                 }
-                let min = data.iter().min().unwrap();
-                let max = data.iter().max().unwrap();
-                self.res.map[idx] = map;
-                self.res.min[idx] = *min;
-                self.res.max[idx] = *max;
             }
+            floor.iters += iter;
+            floor.update();
         }
+        Ok(())
     }
 }
 
@@ -0,0 +1,211 @@
+//! Simulated counter backend for testing without hardware.
+//!
+//! [MockContext] records every [PerfCtlDescriptor] written to it, and
+//! produces scripted counter values instead of reading real PMCs. This makes
+//! it possible to exercise code built on [crate::PMCTest] and
+//! [crate::PMCResults] deterministically on any x86_64 Linux machine.
+//!
+//! There are two ways that scripted values can reach a test:
+//!
+//! - [MockMode::Read]: no counters are readable with RDPMC, so
+//!   [crate::PMCTest::run_iter] reads each counter with
+//!   [PMCBackend::read_ctr] before and after calling into emitted code.
+//! - [MockMode::Stub]: emitted code reads the scripted values itself by
+//!   calling the [ReadStub] returned by [MockContext::read_stub] in place of
+//!   RDPMC (see [crate::emit_rdpmc_test_all]).
+//!
+//! In both cases, each pair of reads on a counter (the start and end of a
+//! measurement) yields the next scripted value as the difference. Counters
+//! without any remaining scripted values yield zero. Like the hardware,
+//! counters wrap around at [crate::pmc::COUNTER_WIDTH] bits.
+//!
+//! ```
+//! use lamina::*;
+//! use lamina::ctx::PMCBackend;
+//! use lamina::mock::{ MockContext, MockMode };
+//! use lamina::pmc::PerfCtlDescriptor;
+//! use lamina::event::Event;
+//!
+//! let mut ctx = MockContext::new(MockMode::Stub);
+//! ctx.script(0, &[3, 1, 2]);
+//!
+//! let pmc = PerfCtlDescriptor::new().set(0, Event::ExRetInstr(0));
+//! ctx.write(&pmc).unwrap();
+//!
+//! let code = emit_rdpmc_test_all!(stub(ctx.read_stub()), ; nop).unwrap();
+//! let mut test = PMCTest::new("mock", &code, &pmc);
+//! test.run_iter(&ctx, 3).unwrap();
+//!
+//! assert_eq!(test.res.data[0], Some(vec![3, 1, 2]));
+//! assert_eq!((test.res.min[0], test.res.max[0]), (1, 3));
+//! assert_eq!(ctx.history.len(), 1);
+//!
+//! // The same values can be read by the caller instead
+//! let mut ctx = MockContext::new(MockMode::Read);
+//! ctx.script(0, &[3, 1, 2]);
+//! let code = emit_rdpmc_test_all!(; nop).unwrap();
+//! let mut test = PMCTest::new("mock", &code, &pmc);
+//! test.run_iter(&ctx, 3).unwrap();
+//! assert_eq!(test.res.data[0], Some(vec![3, 1, 2]));
+//! ```
+//!
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use crate::codegen::ReadStub;
+use crate::ctx::PMCBackend;
+use crate::pmc::{ PerfCtlDescriptor, COUNTER_WIDTH };
+use crate::error::Error;
+
+type Err<T> = Result<T, Error>;
+
+/// Indicates how scripted values are delivered to a test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockMode {
+    /// Counters are read by the caller with [PMCBackend::read_ctr].
+    Read,
+    /// Counters are read by emitted code with [MockContext::read_stub].
+    Stub,
+}
+
+/// Scripted state for all six counters.
+#[derive(Default)]
+struct MockState {
+    /// Remaining scripted values for each counter.
+    script: [VecDeque<u64>; 6],
+    /// The current value of each counter.
+    value: [u64; 6],
+    /// Set when the next read on a counter ends a measurement.
+    pending: [bool; 6],
+}
+impl MockState {
+    /// Return the value of a counter, advancing the script after every
+    /// other read.
+    fn read(&mut self, idx: usize) -> u64 {
+        assert!(idx < 6);
+        if self.pending[idx] {
+            let delta = self.script[idx].pop_front().unwrap_or(0);
+            self.value[idx] = self.value[idx].wrapping_add(delta)
+                & ((1 << COUNTER_WIDTH) - 1);
+        }
+        self.pending[idx] = !self.pending[idx];
+        self.value[idx]
+    }
+}
+
+/// [ReadStubFn](crate::codegen::ReadStubFn) used by [MockMode::Stub].
+extern "C" fn mock_read_stub(arg: *const u8, idx: usize) -> u64 {
+    let state = unsafe { &*(arg as *const RefCell<MockState>) };
+    state.borrow_mut().read(idx)
+}
+
+/// A simulated [PMCBackend].
+pub struct MockContext {
+    /// How scripted values are delivered to a test.
+    pub mode: MockMode,
+    /// Every [PerfCtlDescriptor] written to this context (in order).
+    /// Clearing the context is recorded as an empty descriptor.
+    pub history: Vec<PerfCtlDescriptor>,
+    /// The most recent set of PERF_CTL values.
+    desc: PerfCtlDescriptor,
+    /// Scripted state (boxed, since emitted code holds a pointer to it).
+    state: Box<RefCell<MockState>>,
+}
+
+impl MockContext {
+    /// Create a new context.
+    pub fn new(mode: MockMode) -> Self {
+        Self {
+            mode,
+            history: Vec::new(),
+            desc: PerfCtlDescriptor::new(),
+            state: Box::default(),
+        }
+    }
+
+    /// Append some values to the script for a particular counter.
+    /// Each value is the difference observed by a single measurement.
+    pub fn script(&mut self, idx: usize, values: &[u64]) {
+        assert!(idx < 6);
+        self.state.borrow_mut().script[idx].extend(values);
+    }
+
+    /// Set the current value of a particular counter (i.e. to simulate a
+    /// counter which is about to wrap around).
+    ///
+    /// ```
+    /// use lamina::*;
+    /// use lamina::ctx::PMCBackend;
+    /// use lamina::mock::{ MockContext, MockMode };
+    /// use lamina::pmc::{ PerfCtlDescriptor, COUNTER_WIDTH };
+    /// use lamina::event::Event;
+    ///
+    /// let mut ctx = MockContext::new(MockMode::Read);
+    /// ctx.preset(0, (1 << COUNTER_WIDTH) - 2);
+    /// ctx.script(0, &[5, 5]);
+    ///
+    /// let pmc = PerfCtlDescriptor::new().set(0, Event::ExRetInstr(0));
+    /// let code = emit_rdpmc_test_all!(; nop).unwrap();
+    /// let mut test = PMCTest::new("wrap", &code, &pmc);
+    /// test.res.width = COUNTER_WIDTH;
+    /// test.run_iter(&ctx, 2).unwrap();
+    /// assert_eq!(test.res.data[0], Some(vec![5, 5]));
+    /// assert_eq!(test.res.wrapped[0], 1);
+    ///
+    /// // Counters without an event are ignored (even if they wrap around)
+    /// let mut ctx = MockContext::new(MockMode::Stub);
+    /// ctx.preset(2, (1 << COUNTER_WIDTH) - 2);
+    /// ctx.script(2, &[5]);
+    /// let code = emit_rdpmc_test_all!(stub(ctx.read_stub()), ; nop).unwrap();
+    /// let mut test = PMCTest::new("unused", &code, &pmc);
+    /// test.res.width = COUNTER_WIDTH;
+    /// test.run_iter(&ctx, 1).unwrap();
+    /// assert_eq!(test.res.wrapped, [0; 6]);
+    /// ```
+    pub fn preset(&mut self, idx: usize, value: u64) {
+        assert!(idx < 6);
+        self.state.borrow_mut().value[idx] = value & ((1 << COUNTER_WIDTH) - 1);
+    }
+
+    /// Return a [ReadStub] for use in emitted code.
+    ///
+    /// # Safety
+    /// The stub holds a pointer to the state of this context; emitted code
+    /// must not be called after the context is dropped.
+    pub fn read_stub(&self) -> ReadStub {
+        ReadStub {
+            func: mock_read_stub,
+            arg: &*self.state as *const RefCell<MockState> as *const u8,
+        }
+    }
+}
+
+impl PMCBackend for MockContext {
+    fn write(&mut self, d: &PerfCtlDescriptor) -> Err<()> {
+        self.desc = *d;
+        self.history.push(*d);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Err<()> {
+        self.desc.clear_all();
+        self.history.push(self.desc);
+        Ok(())
+    }
+
+    fn desc(&self) -> &PerfCtlDescriptor { &self.desc }
+
+    fn rdpmc_index(&self, idx: usize) -> Option<u32> {
+        assert!(idx < 6);
+        match self.mode {
+            MockMode::Read => None,
+            MockMode::Stub => Some(idx as u32),
+        }
+    }
+
+    fn read_ctr(&self, idx: usize) -> Err<u64> {
+        Ok(self.state.borrow_mut().read(idx))
+    }
+}
+
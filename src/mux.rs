@@ -0,0 +1,341 @@
+//! Measuring any number of events by running a test once for each group
+//! of (up to six) events.
+//!
+//! A [Multiplexer] assigns a list of events to counters, splitting them
+//! into groups which can be counted at the same time. Counter constraints
+//! are respected (see [crate::pmc::CONSTRAINTS]), and events which need a
+//! merge event are paired with one automatically.
+//!
+//! ```no_run
+//! use lamina::*;
+//! use lamina::event::Event;
+//! use lamina::pmc::PerfCtlDescriptor;
+//!
+//! fn main() -> Result<(), lamina::Error> {
+//!     lamina::util::pin_to_core(0)?;
+//!     let mut ctx = lamina::ctx::open_backend()?;
+//!     let code = emit_rdpmc_test_all!(; nop).unwrap();
+//!     let mut test = PMCTest::new("nop", &code, &PerfCtlDescriptor::new());
+//!
+//!     let events: Vec<Event> = (0x20..0x30)
+//!         .map(|e| Event::Undefined(e, 0xff))
+//!         .collect();
+//!     let res = test.run_multiplexed(ctx.as_mut(), &events, 1024)?;
+//!     res.print();
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Note that each group is measured during a different set of runs.
+//!
+
+use crate::PMCTest;
+use crate::ctx::PMCBackend;
+use crate::event::Event;
+use crate::pmc::{ PerfCtl, PerfCtlDescriptor, CONSTRAINTS };
+use crate::stats;
+use crate::error::Error;
+
+type Err<T> = Result<T, Error>;
+
+/// A set of groups of events, each of which can be counted at once.
+#[derive(Clone, Debug)]
+pub struct Multiplexer {
+    /// The set of events (in the order they were given).
+    pub events: Vec<Event>,
+    /// The `PERF_CTL` value for each event.
+    pub ctls: Vec<PerfCtl>,
+    pub groups: Vec<PerfCtlDescriptor>,
+    /// The number of iterations run (and discarded) before measuring each
+    /// group.
+    pub warmup: usize,
+}
+impl Multiplexer {
+    /// Assign some events to the `available` counters (with the default
+    /// `PERF_CTL` values, see [PerfCtl::new]).
+    pub fn new(events: &[Event], available: [bool; 6]) -> Err<Self> {
+        let ctls: Vec<PerfCtl> = events.iter()
+            .map(|e| PerfCtl::new(*e, true))
+            .collect();
+        Self::from_ctls(events, &ctls, available)
+    }
+
+    /// Assign some events (each with a particular `PERF_CTL` value) to the
+    /// `available` counters.
+    ///
+    /// Duplicate events are only counted once, although the same event may
+    /// be counted more than once with different `PERF_CTL` values. Merge
+    /// events are added automatically, and cannot be used here.
+    ///
+    /// ```
+    /// use lamina::mux::Multiplexer;
+    ///
+    /// let (events, ctls) = lamina::spec::parse_events(
+    ///     &["r0c0", "r0c0,cmask=1", "r0c0"]
+    /// ).unwrap();
+    ///
+    /// let mux = Multiplexer::from_ctls(&events, &ctls, [true; 6]).unwrap();
+    /// assert_eq!(mux.events.len(), 2);
+    /// assert_eq!(mux.ctls[1].count_mask(), 1);
+    /// assert_eq!(mux.groups.len(), 1);
+    ///
+    /// // Both are measured, and can be told apart in the results
+    /// use lamina::*;
+    /// use lamina::mock::{ MockContext, MockMode };
+    /// use lamina::pmc::PerfCtlDescriptor;
+    ///
+    /// let mut ctx = MockContext::new(MockMode::Read);
+    /// ctx.script(0, &[10, 10]);
+    /// ctx.script(1, &[4, 4]);
+    /// let code = emit_rdpmc_test_all!(; nop).unwrap();
+    /// let mut test = PMCTest::new("cmask", &code, &PerfCtlDescriptor::new());
+    /// let res = mux.run(&mut ctx, &mut test, 2).unwrap();
+    /// assert_eq!(res.get(&events[0], &ctls[0]).unwrap().data, vec![10, 10]);
+    /// assert_eq!(res.get(&events[1], &ctls[1]).unwrap().data, vec![4, 4]);
+    /// ```
+    pub fn from_ctls(events: &[Event], ctls: &[PerfCtl], available: [bool; 6])
+        -> Err<Self>
+    {
+        assert_eq!(events.len(), ctls.len());
+        let mut entries: Vec<(Event, PerfCtl, Slots)> = Vec::new();
+        for (e, ctl) in events.iter().zip(ctls.iter()) {
+            if *e == Event::Merge {
+                return Err(Error::NoCounter { event: e.to_string() });
+            }
+            if entries.iter().any(|(x, c, _)| x == e && c == ctl) {
+                continue;
+            }
+            entries.push((*e, *ctl, Slots::new(e, available)));
+        }
+
+        // Place the most constrained events first
+        let mut order: Vec<usize> = (0..entries.len()).collect();
+        order.sort_by_key(|idx| entries[*idx].2.rank());
+
+        let mut groups: Vec<PerfCtlDescriptor> = Vec::new();
+        for idx in order {
+            let (e, ctl, slots) = entries[idx];
+            let placed = groups.iter_mut()
+                .any(|group| slots.place(group, e, ctl));
+            if !placed {
+                let mut group = PerfCtlDescriptor::new();
+                if !slots.place(&mut group, e, ctl) {
+                    return Err(Error::NoCounter { event: e.to_string() });
+                }
+                groups.push(group);
+            }
+        }
+        let events = entries.iter().map(|(e, ..)| *e).collect();
+        let ctls = entries.iter().map(|(_, ctl, _)| *ctl).collect();
+        Ok(Self { events, ctls, groups, warmup: 0 })
+    }
+
+    /// Run some number of iterations before measuring each group, in order
+    /// to warm up caches and predictors.
+    pub fn warmup(mut self, iters: usize) -> Self {
+        self.warmup = iters;
+        self
+    }
+
+    /// Run a test once for each group (see [PMCTest::run_iter]),
+    /// collecting results for each event.
+    ///
+    /// This discards any results already collected by the test, and leaves
+    /// the results for the last group in the test. The counters are cleared
+    /// after each group, even if the test fails:
+    ///
+    /// ```
+    /// use lamina::*;
+    /// use lamina::ctx::PMCBackend;
+    /// use lamina::event::Event;
+    /// use lamina::irq::IrqGuard;
+    /// use lamina::mock::{ MockContext, MockMode };
+    /// use lamina::mux::Multiplexer;
+    /// use lamina::pmc::PerfCtlDescriptor;
+    ///
+    /// let mut ctx = MockContext::new(MockMode::Read);
+    /// let code = emit_rdpmc_test_all!(; nop).unwrap();
+    /// let mut test = PMCTest::new("nop", &code, &PerfCtlDescriptor::new())
+    ///     .discard_interrupts(IrqGuard::Counters);
+    ///
+    /// // There's no counter for interrupts
+    /// let mux = Multiplexer::new(&[Event::ExRetInstr(0)], [true; 6])
+    ///     .unwrap();
+    /// assert!(mux.run(&mut ctx, &mut test, 1).is_err());
+    /// assert!(ctx.desc().events.iter().all(|e| e.is_none()));
+    /// ```
+    pub fn run(&self, ctx: &mut dyn PMCBackend, test: &mut PMCTest,
+        iters: usize) -> Err<MuxResults>
+    {
+        let mut res = MuxResults { name: test.name, events: Vec::new() };
+        for (group_idx, group) in self.groups.iter().enumerate() {
+            ctx.write(group)?;
+            let measured = (|| {
+                // Results for the warmup are collected for this group (and
+                // then discarded), not for the events in the previous group
+                test.reset(group);
+                if self.warmup > 0 {
+                    test.run_iter(ctx, self.warmup)?;
+                    test.reset(group);
+                }
+                test.run_iter(ctx, iters)
+            })();
+            // Stop the counters, even if the test failed
+            let cleared = ctx.clear();
+            measured?;
+            cleared?;
+
+            for idx in 0..6 {
+                let (event, data) = match (&test.res.event[idx],
+                    &test.res.data[idx])
+                {
+                    (Some(Event::Merge), _) => continue,
+                    (Some(e), Some(d)) => (*e, d.clone()),
+                    _ => continue,
+                };
+                res.events.push(EventResult {
+                    event,
+                    ctl: group.ctl[idx].unwrap(),
+                    group: group_idx,
+                    counter: idx,
+                    data,
+                    floor: test.res.floor.as_ref()
+                        .and_then(|f| f.data[idx].clone()),
+                });
+            }
+        }
+        res.events.sort_by_key(|r| {
+            self.events.iter().zip(self.ctls.iter())
+                .position(|(e, ctl)| *e == r.event && *ctl == r.ctl)
+        });
+        Ok(res)
+    }
+}
+
+/// The counters which can be used for some event.
+#[derive(Clone, Copy)]
+struct Slots {
+    /// Bitmask of usable counters.
+    mask: u8,
+    /// Whether a merge event is needed on the next counter.
+    merge: bool,
+}
+impl Slots {
+    fn new(e: &Event, available: [bool; 6]) -> Self {
+        let mut mask = available.iter().enumerate()
+            .fold(0u8, |acc, (idx, en)| acc | ((*en as u8) << idx));
+        let mut merge = false;
+        if !e.is_software() {
+            let select = e.convert().0;
+            if let Some(c) = CONSTRAINTS.iter().find(|c| c.select == select) {
+                // The merge event also needs to be on an available counter
+                if c.merge {
+                    mask &= mask >> 1;
+                }
+                mask &= c.counters;
+                merge = c.merge;
+            }
+        }
+        Self { mask, merge }
+    }
+
+    /// Events with fewer usable counters are placed first.
+    fn rank(&self) -> (bool, u32) {
+        (!self.merge, self.mask.count_ones())
+    }
+
+    /// Try to place an event in the first usable counter in some group.
+    fn place(&self, group: &mut PerfCtlDescriptor, e: Event, ctl: PerfCtl)
+        -> bool
+    {
+        let free = |idx: usize| idx < 6 && group.events[idx].is_none();
+        let idx = (0..6).find(|idx| {
+            (self.mask >> idx) & 1 != 0 && free(*idx)
+                && (!self.merge || free(idx + 1))
+        });
+        match idx {
+            Some(idx) => {
+                group.events[idx] = Some(e);
+                group.ctl[idx] = Some(ctl);
+                if self.merge {
+                    group.events[idx + 1] = Some(Event::Merge);
+                    group.ctl[idx + 1] = Some(PerfCtl::new_merge(true));
+                }
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// Results for a single event (see [Multiplexer::run]).
+#[derive(Clone, Debug)]
+pub struct EventResult {
+    pub event: Event,
+    /// The `PERF_CTL` value used for this event.
+    pub ctl: PerfCtl,
+    /// The group that this event was counted in.
+    pub group: usize,
+    /// The counter used for this event.
+    pub counter: usize,
+    pub data: Vec<usize>,
+    /// Data for this event from the floor (if any, see
+    /// [PMCTest::with_floor]).
+    pub floor: Option<Vec<usize>>,
+}
+impl EventResult {
+    /// Return summary statistics (if there's any data).
+    pub fn summary(&self) -> Option<stats::Summary> {
+        use stats::Samples;
+        if self.data.is_empty() { None } else { Some(self.data.summary()) }
+    }
+
+    /// Return the data with the overhead of measurement (the median value
+    /// from the floor) subtracted, if a floor was measured.
+    pub fn corrected(&self) -> Option<Vec<usize>> {
+        use stats::Samples;
+        let floor = self.floor.as_ref().filter(|f| !f.is_empty())?;
+        let overhead = floor.median().round() as usize;
+        Some(self.data.iter().map(|x| x.saturating_sub(overhead)).collect())
+    }
+}
+
+/// Results for some set of events, keyed by event and `PERF_CTL` value.
+#[derive(Clone, Debug)]
+pub struct MuxResults {
+    /// The name of the test.
+    pub name: &'static str,
+    /// Results for each event (in the same order as [Multiplexer::events]).
+    pub events: Vec<EventResult>,
+}
+impl MuxResults {
+    /// Return the results for some event with a particular `PERF_CTL`
+    /// value.
+    pub fn get(&self, e: &Event, ctl: &PerfCtl) -> Option<&EventResult> {
+        self.events.iter().find(|r| r.event == *e && r.ctl == *ctl)
+    }
+
+    pub fn print(&self) {
+        println!("# Test '{}' ({} events)", self.name, self.events.len());
+        for r in self.events.iter() {
+            println!("|  PMCx{:03x} [{}] (group {}, counter {})",
+                r.event.convert().0, r.event, r.group, r.counter
+            );
+            println!("|   Description:  {}", r.event.desc().desc);
+            if let Some(s) = r.summary() {
+                println!("|   {}", s);
+            }
+        }
+    }
+}
+
+impl PMCTest {
+    /// Run this test for any number of events, once for each group of
+    /// events that can be counted at once (see [Multiplexer]).
+    pub fn run_multiplexed(&mut self, ctx: &mut dyn PMCBackend,
+        events: &[Event], iters: usize) -> Err<MuxResults>
+    {
+        let mux = Multiplexer::new(events, self.source.counters())?;
+        mux.run(ctx, self, iters)
+    }
+}
@@ -0,0 +1,267 @@
+//! Counter backend built on the Linux `perf_event_open()` interface.
+//!
+//! This is an alternative to [crate::ctx::PMCContext] for machines where the
+//! kernel module isn't available (i.e. stock kernels, or virtual machines).
+//! Each `PERF_CTL` value is handed to the kernel as a `PERF_TYPE_RAW` event,
+//! and the kernel decides which physical counter is used for each event.
+//! The index used to read each counter with RDPMC is obtained from the
+//! `perf_event_mmap_page` associated with each event.
+//!
+//! ## Caveats
+//!
+//! - The kernel only allows RDPMC in userspace when
+//!   `/sys/bus/event_source/devices/cpu/rdpmc` is non-zero. Otherwise,
+//!   counters are read with `read()` before and after calling emitted code.
+//! - Software events ([crate::event::SoftwareEvent]) are never readable with RDPMC.
+//! - Merge events are unsupported (the kernel manages counter pairs itself).
+//!
+
+use crate::ctx::PMCBackend;
+use crate::event::Event;
+use crate::pmc::{ PerfCtl, PerfCtlDescriptor };
+use crate::error::{ Error, Op };
+use nix::errno::Errno;
+use nix::libc;
+
+type Err<T> = Result<T, Error>;
+
+/// `perf_event_attr.type` for software events.
+const PERF_TYPE_SOFTWARE: u32 = 1;
+/// `perf_event_attr.type` for raw PMC events.
+const PERF_TYPE_RAW: u32 = 4;
+
+// Bits in the `perf_event_attr` bitfield.
+const ATTR_PINNED: u64          = 1 << 2;
+const ATTR_EXCLUDE_USER: u64    = 1 << 4;
+const ATTR_EXCLUDE_KERNEL: u64  = 1 << 5;
+const ATTR_EXCLUDE_HOST: u64    = 1 << 19;
+const ATTR_EXCLUDE_GUEST: u64   = 1 << 20;
+
+/// `perf_event_mmap_page.capabilities` - RDPMC is allowed in userspace.
+const CAP_USER_RDPMC: u64 = 1 << 2;
+
+/// Kernel FFI - `struct perf_event_attr` (`PERF_ATTR_SIZE_VER5`).
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved_2: u16,
+}
+
+/// Kernel FFI - the leading fields of `struct perf_event_mmap_page`.
+#[repr(C)]
+#[allow(dead_code)]
+struct PerfEventMmapPage {
+    version: u32,
+    compat_version: u32,
+    lock: u32,
+    index: u32,
+    offset: i64,
+    time_enabled: u64,
+    time_running: u64,
+    capabilities: u64,
+    pmc_width: u16,
+}
+
+/// A single event opened with `perf_event_open()`.
+struct PerfEvent {
+    /// File descriptor for the event.
+    fd: i32,
+    /// Pointer to the first page mapped from the file descriptor.
+    page: *mut PerfEventMmapPage,
+}
+impl PerfEvent {
+    /// Open an event on the calling thread (on any CPU).
+    fn open(attr: &PerfEventAttr) -> Err<Self> {
+        let fd = unsafe {
+            libc::syscall(libc::SYS_perf_event_open,
+                attr as *const PerfEventAttr, 0, -1, -1, 0
+            )
+        } as i32;
+        if fd < 0 {
+            return Err(Error::Perf { 
+                op: Op::PerfEventOpen, errno: Errno::last() 
+            });
+        }
+
+        let page = unsafe {
+            libc::mmap(std::ptr::null_mut(), 4096, libc::PROT_READ,
+                libc::MAP_SHARED, fd, 0
+            )
+        };
+        if page == libc::MAP_FAILED {
+            let errno = Errno::last();
+            unsafe { libc::close(fd); }
+            return Err(Error::Perf { op: Op::Mmap, errno });
+        }
+        Ok(Self { fd, page: page as *mut PerfEventMmapPage })
+    }
+
+    /// Return the RDPMC index for this event (if any).
+    ///
+    /// The kernel may update the page at any time, so this follows the
+    /// sequence-lock protocol described in `include/uapi/linux/perf_event.h`.
+    fn rdpmc_index(&self) -> Option<u32> {
+        use std::ptr::{ addr_of, read_volatile };
+        use std::sync::atomic::{ compiler_fence, Ordering };
+        let (caps, index) = unsafe {
+            loop {
+                let seq = read_volatile(addr_of!((*self.page).lock));
+                compiler_fence(Ordering::SeqCst);
+                let caps = read_volatile(addr_of!((*self.page).capabilities));
+                let index = read_volatile(addr_of!((*self.page).index));
+                compiler_fence(Ordering::SeqCst);
+                if read_volatile(addr_of!((*self.page).lock)) == seq {
+                    break (caps, index);
+                }
+            }
+        };
+        if (caps & CAP_USER_RDPMC) != 0 && index != 0 {
+            Some(index - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Read the current value of this event with `read()`.
+    fn read(&self) -> Err<u64> {
+        let mut val = 0u64;
+        let res = unsafe {
+            libc::read(self.fd, &mut val as *mut u64 as *mut libc::c_void, 8)
+        };
+        if res != 8 {
+            return Err(Error::Perf { op: Op::ReadCtr, errno: Errno::last() });
+        }
+        Ok(val)
+    }
+}
+impl std::ops::Drop for PerfEvent {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.page as *mut libc::c_void, 4096);
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Container for a set of events opened with `perf_event_open()`.
+///
+/// Each entry in a [PerfCtlDescriptor] is opened as a separate event
+/// attached to the calling thread. Events are closed when a new descriptor
+/// is written, and when the context is dropped.
+pub struct PerfEventContext {
+    /// The set of open events.
+    events: [Option<PerfEvent>; 6],
+    /// The most recent set of PERF_CTL values.
+    desc: PerfCtlDescriptor,
+}
+
+impl PerfEventContext {
+
+    /// Bits in `PERF_CTL` which are passed to the kernel in a raw event.
+    /// The kernel expects these to be in the same position as `PERF_CTL`.
+    pub const RAW_CONFIG_MASK: usize = {
+          PerfCtl::EVTSEL_HI_MASK | PerfCtl::CNTMASK_MASK
+        | PerfCtl::INV_MASK | PerfCtl::EDGE_MASK
+        | PerfCtl::UNITMASK_MASK | PerfCtl::EVTSEL_LO_MASK
+    };
+
+    /// Create a new context.
+    pub fn new() -> Err<Self> {
+        Ok(Self { events: Default::default(), desc: PerfCtlDescriptor::new() })
+    }
+
+    /// Build the `perf_event_attr` for a particular entry.
+    fn attr(e: Event, ctl: PerfCtl) -> Err<PerfEventAttr> {
+        let mut attr = PerfEventAttr {
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            ..Default::default()
+        };
+        match e {
+            Event::Merge => {
+                return Err(Error::Unsupported {
+                    op: Op::PerfEventOpen, 
+                    reason: "merge events are managed by the kernel",
+                });
+            },
+            Event::Software(sw) => {
+                attr.type_ = PERF_TYPE_SOFTWARE;
+                attr.config = sw as u64;
+            },
+            _ => {
+                attr.type_ = PERF_TYPE_RAW;
+                attr.config = (ctl.0 & Self::RAW_CONFIG_MASK) as u64;
+                attr.flags |= ATTR_PINNED;
+                if (ctl.osuser() & 0b01) == 0 {
+                    attr.flags |= ATTR_EXCLUDE_USER;
+                }
+                if (ctl.osuser() & 0b10) == 0 {
+                    attr.flags |= ATTR_EXCLUDE_KERNEL;
+                }
+                match ctl.hostguest() {
+                    0b01 => attr.flags |= ATTR_EXCLUDE_HOST,
+                    0b10 => attr.flags |= ATTR_EXCLUDE_GUEST,
+                    _ => {},
+                }
+            },
+        }
+        Ok(attr)
+    }
+}
+
+impl PMCBackend for PerfEventContext {
+    fn write(&mut self, d: &PerfCtlDescriptor) -> Err<()> {
+        d.validate()?;
+        self.clear()?;
+        for idx in 0..6 {
+            if let (Some(e), Some(ctl)) = (d.events[idx], d.ctl[idx]) {
+                if !ctl.en() {
+                    continue;
+                }
+                self.events[idx] = Some(PerfEvent::open(&Self::attr(e, ctl)?)?);
+            }
+        }
+        self.desc = *d;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Err<()> {
+        self.events = Default::default();
+        self.desc.clear_all();
+        Ok(())
+    }
+
+    fn desc(&self) -> &PerfCtlDescriptor { &self.desc }
+
+    fn rdpmc_index(&self, idx: usize) -> Option<u32> {
+        self.events[idx].as_ref().and_then(|e| e.rdpmc_index())
+    }
+
+    fn read_ctr(&self, idx: usize) -> Err<u64> {
+        match &self.events[idx] {
+            Some(e) => e.read(),
+            None => Err(Error::Unsupported {
+                op: Op::ReadCtr, reason: "no event open for this counter",
+            }),
+        }
+    }
+}
+
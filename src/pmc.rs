@@ -3,8 +3,33 @@
 //! The [PerfCtl] type is used to create a valid value for a particular 
 //! `PERF_CTL` MSR. [PerfCtlDescriptor] represents a set of all six `PERF_CTL`
 //! MSR values at some instant.
+//!
+//! [PerfCtl] values can be built by chaining the `with_*` functions, i.e.
+//! for counting cycles where at least four ops are retired:
+//!
+//! ```
+//! use lamina::event::Event;
+//! use lamina::pmc::{ PerfCtl, PerfCtlDescriptor };
+//!
+//! let pmc = PerfCtlDescriptor::new()
+//!     .set_ctl(0, PerfCtl::new(Event::ExRetCops(0), true).with_count_mask(4));
+//! assert_eq!(pmc.ctl[0].unwrap().count_mask(), 4);
+//! ```
+//!
+//! Both [Event] and [PerfCtl] can be parsed from strings like
+//! `event=0xae,umask=0x02,cmask=1` (see [crate::spec]).
 
 use crate::event::*;
+use crate::catalog::{ Catalog, EventDef };
+use crate::error::Error;
+
+/// The width of each counter (in bits).
+///
+/// The difference between two reads of a counter is only meaningful in the
+/// low bits, see [crate::cpuid::counter_width] and [crate::PMCResults].
+/// When a merge event is used, the odd-numbered counter holds the bits
+/// above this in the value of the even-numbered counter.
+pub const COUNTER_WIDTH: u32 = 48;
 
 /// Wrapper type for the set of all `PERF_CTL` bits.
 #[derive(Clone, Copy, Debug)]
@@ -51,8 +76,213 @@ impl PerfCtlDescriptor {
         self.events[idx] = Some(e);
         self
     }
+
+    /// Set a particular entry to some `PERF_CTL` value.
+    ///
+    /// The entry keeps its current event if the event select and unit mask
+    /// haven't changed (i.e. when adjusting an entry added with 
+    /// [PerfCtlDescriptor::set]). Otherwise, the event is taken from the 
+    /// `PERF_CTL` value (see [PerfCtl::event]).
+    pub fn set_ctl(mut self, idx: usize, ctl: PerfCtl) -> Self {
+        assert!(idx < 6);
+        let e = ctl.event();
+        if e == Event::Merge && (idx & 1) == 0 {
+            panic!("Merge behavior undefined for even-numbered counters");
+        }
+        self.events[idx] = match self.events[idx] {
+            Some(old) if old.convert() == e.convert() => Some(old),
+            _ => Some(e),
+        };
+        self.ctl[idx] = Some(ctl);
+        self
+    }
+
+    /// Returns true if some entry is merged with a merge event on the next
+    /// counter (forming a single, wider counter).
+    pub fn is_merged(&self, idx: usize) -> bool {
+        is_merged(&self.events, idx)
+    }
+
+    /// Check that this descriptor can be written to the PMCs. 
+    ///
+    /// This checks that:
+    ///
+    /// - Each event has a matching `PERF_CTL` value without reserved bits
+    /// - Merge events are on odd-numbered counters, following an event on
+    ///   the previous counter
+    /// - Events are only on counters allowed by [CONSTRAINTS] (an event
+    ///   which should have a merge event can still be counted without one,
+    ///   although the high bits of the count are lost)
+    /// - Events and unit masks are valid for the running CPU (see 
+    ///   [PerfCtlDescriptor::check_native]), and unit masks for raw events
+    ///   are valid in the installed (or native) catalog, if the event is
+    ///   defined there
+    ///
+    /// The hardware backends call this before writing anything.
+    ///
+    /// ```
+    /// use lamina::event::Event;
+    /// use lamina::pmc::{ PerfCtl, PerfCtlDescriptor };
+    ///
+    /// let err = |pmc: PerfCtlDescriptor| pmc.validate().unwrap_err()
+    ///     .to_string();
+    ///
+    /// // PMCx003 is merged with the next counter, but doesn't need to be
+    /// let fp = Event::Undefined(0x03, 0xff);
+    /// let pmc = PerfCtlDescriptor::new().set(0, fp).set(1, Event::Merge);
+    /// assert!(pmc.validate().is_ok());
+    /// assert!(PerfCtlDescriptor::new().set(0, fp).validate().is_ok());
+    ///
+    /// // Merge events must be on an odd-numbered counter, after an event
+    /// let e = Event::Undefined(0xc0, 0x00);
+    /// let mut pmc = PerfCtlDescriptor::new().set(1, e);
+    /// pmc.events[2] = Some(Event::Merge);
+    /// pmc.ctl[2] = Some(PerfCtl::new_merge(true));
+    /// assert!(err(pmc).contains("odd-numbered"));
+    /// let pmc = PerfCtlDescriptor::new().set(3, Event::Merge);
+    /// assert!(err(pmc).contains("must follow an event"));
+    ///
+    /// // Reserved bits can't be set
+    /// let ctl = PerfCtl(PerfCtl::new(e, true).0 | (1 << 21));
+    /// let pmc = PerfCtlDescriptor::new().set_ctl(0, ctl);
+    /// assert!(err(pmc).contains("reserved bits"));
+    ///
+    /// // The PERF_CTL value must match the event
+    /// let mut pmc = PerfCtlDescriptor::new().set(0, e);
+    /// pmc.ctl[0] = Some(PerfCtl::new(Event::Undefined(0xc0, 0x01), true));
+    /// assert!(err(pmc).contains("doesn't match"));
+    /// ```
+    pub fn validate(&self) -> Result<(), Error> {
+        for idx in 0..6 {
+            self.validate_entry(idx)?;
+        }
+        self.check_native()?;
+
+        let catalog = Catalog::installed().or_else(|| Catalog::native().ok());
+        if let Some(catalog) = catalog {
+            for e in self.events.iter().flatten() {
+                if let Event::Undefined(select, umask) = e {
+                    match catalog.by_select(*select) {
+                        Some(def) if !def.is_valid_umask(*umask) => {
+                            return Err(Error::InvalidUnitMask {
+                                event: def.name.to_string(), umask: *umask
+                            });
+                        },
+                        _ => {},
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a single entry (see [PerfCtlDescriptor::validate]).
+    fn validate_entry(&self, idx: usize) -> Result<(), Error> {
+        let err = |reason: String| Error::InvalidDescriptor { idx, reason };
+        let (e, ctl) = match (self.events[idx], self.ctl[idx]) {
+            (None, None) => return Ok(()),
+            (Some(e), Some(ctl)) => (e, ctl),
+            (Some(_), None) => return Err(err("no PERF_CTL value".into())),
+            (None, Some(_)) => return Err(err("no event".into())),
+        };
+        if ctl.reserved() != 0 {
+            return Err(err(format!("reserved bits are set ({:#x})", 
+                ctl.reserved())));
+        }
+        match e {
+            Event::Software(_) => return Ok(()),
+            Event::Merge => {
+                if (idx & 1) == 0 {
+                    return Err(err("merge events must be on an \
+                        odd-numbered counter".into()));
+                }
+                if (ctl.0 & !PerfCtl::EN_MASK) != PerfCtl::new_merge(false).0 {
+                    return Err(err("merge events must have all bits \
+                        unset except for the enable bit".into()));
+                }
+                match self.events[idx - 1] {
+                    Some(Event::Merge) | Some(Event::Software(_)) | None => {
+                        return Err(err(format!("merge events must follow \
+                            an event on counter {}", idx - 1)));
+                    },
+                    _ => {},
+                }
+                return Ok(());
+            },
+            _ => {},
+        }
+
+        let (select, umask) = e.convert();
+        if (ctl.event_select() as u16, ctl.unit_mask() as u8) 
+            != (select, umask) 
+        {
+            return Err(err(format!("PERF_CTL value doesn't match '{}'", e)));
+        }
+        if let Some(c) = CONSTRAINTS.iter().find(|c| c.select == select) {
+            if (c.counters >> idx) & 1 == 0 {
+                return Err(err(format!("PMCx{:03x} {}, and can only use \
+                    counters with mask {:#08b}", select, c.reason, 
+                    c.counters)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check all events against some [Catalog] (see [Catalog::check_event]).
+    pub fn check_catalog(&self, catalog: &Catalog) -> Result<(), Error> {
+        for e in self.events.iter().flatten() {
+            catalog.check_event(e)?;
+        }
+        Ok(())
+    }
+
+    /// Check all events against the catalog for the running CPU.
+    ///
+    /// Descriptors with only raw events ([Event::Undefined]), merge events, 
+    /// and software events are accepted on any CPU.
+    pub fn check_native(&self) -> Result<(), Error> {
+        if self.events.iter().flatten().any(|e| e.is_named()) {
+            self.check_catalog(Catalog::native()?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if some entry in a set of events is an even-numbered 
+/// counter followed by a merge event.
+pub(crate) fn is_merged(events: &[Option<Event>; 6], idx: usize) -> bool {
+    (idx & 1) == 0 && idx < 5 
+        && !matches!(events[idx], None | Some(Event::Merge)) 
+        && events[idx + 1] == Some(Event::Merge)
 }
 
+/// Restrictions on the counters that can be used to count some event.
+#[derive(Clone, Copy, Debug)]
+pub struct CounterConstraint {
+    /// The event select code.
+    pub select: u16,
+    /// Bitmask of the counters that can be used for this event.
+    pub counters: u8,
+    /// Whether a merge event should be used on the next (odd-numbered)
+    /// counter. Without one, the count may be truncated.
+    pub merge: bool,
+    /// Why this event is restricted.
+    pub reason: &'static str,
+}
+
+/// Known counter constraints for events on Zen 2 (and later).
+///
+/// Events that can increment by more than 15 in a single cycle should be
+/// counted with a merge event (also see `amd_is_pair_event_code()` in the
+/// Linux kernel).
+pub const CONSTRAINTS: &[CounterConstraint] = &[
+    // PMCx003 "Retired SSE/AVX Operations"
+    CounterConstraint {
+        select: 0x003, counters: 0b010101, merge: true,
+        reason: "can count more than 15 events per cycle",
+    },
+];
+
 /// Representing the host/guest field in a [PerfCtl] register.
 #[derive(Clone, Copy, Debug)]
 pub enum HostGuestBits {
@@ -72,7 +302,7 @@ pub enum OSUserBits {
 
 /// Wrapper type for the value of a `PERF_CTL` MSR.
 #[repr(transparent)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PerfCtl(pub usize);
 impl PerfCtl {
 
@@ -137,6 +367,13 @@ impl PerfCtl {
         0b00_0000_0000_00000000_0_0_0_0_0_0_00_00000000_11111111
     };
 
+    // Bits which aren't part of any field above
+    pub const RESERVED_MASK: usize = !(Self::HOSTGUEST_MASK 
+        | Self::EVTSEL_HI_MASK | Self::CNTMASK_MASK | Self::INV_MASK 
+        | Self::EN_MASK | Self::INT_MASK | Self::EDGE_MASK 
+        | Self::OSUSER_MASK | Self::UNITMASK_MASK | Self::EVTSEL_LO_MASK
+    );
+
     pub fn hostguest(&self) -> usize { (self.0 & Self::HOSTGUEST_MASK) >> 40 }
     pub fn event_select(&self) -> usize { 
           (self.0 & Self::EVTSEL_HI_MASK) >> 24 
@@ -149,6 +386,16 @@ impl PerfCtl {
     pub fn edge(&self) -> bool { (self.0 & Self::EDGE_MASK) != 0 }
     pub fn osuser(&self) -> usize { (self.0 & Self::OSUSER_MASK) >> 16 }
     pub fn unit_mask(&self) -> usize { (self.0 & Self::UNITMASK_MASK) >> 8 }
+    pub fn reserved(&self) -> usize { self.0 & Self::RESERVED_MASK }
+
+    /// Return the event selected by this value, either [Event::Merge] or
+    /// some [Event::Undefined].
+    pub fn event(&self) -> Event {
+        match (self.event_select() as u16, self.unit_mask() as u8) {
+            (0xfff, _) => Event::Merge,
+            (e, m) => Event::Undefined(e, m),
+        }
+    }
 }
 
 impl PerfCtl {
@@ -222,3 +469,141 @@ impl PerfCtl {
     }
 }
 
+/// Chainable versions of the `set_*` functions, i.e.
+/// `PerfCtl::new(e, true).with_count_mask(1).with_inv(true)`.
+impl PerfCtl {
+    pub fn with_hostguest(mut self, x: HostGuestBits) -> Self {
+        self.set_hostguest(x); self
+    }
+    pub fn with_event_select(mut self, x: u16) -> Self {
+        self.set_event_select(x); self
+    }
+    pub fn with_count_mask(mut self, x: usize) -> Self {
+        self.set_count_mask(x); self
+    }
+    pub fn with_inv(mut self, x: bool) -> Self {
+        self.set_inv(x); self
+    }
+    pub fn with_en(mut self, x: bool) -> Self {
+        self.set_en(x); self
+    }
+    pub fn with_int(mut self, x: bool) -> Self {
+        self.set_int(x); self
+    }
+    pub fn with_edge(mut self, x: bool) -> Self {
+        self.set_edge(x); self
+    }
+    pub fn with_osuser(mut self, x: OSUserBits) -> Self {
+        self.set_osuser(x); self
+    }
+    pub fn with_unit_mask(mut self, x: u8) -> Self {
+        self.set_unit_mask(x); self
+    }
+}
+
+
+/// A human-readable description of some `PERF_CTL` value, with names for
+/// the event and unit mask taken from a [Catalog] (see [PerfCtl::decode]).
+#[derive(Clone, Debug)]
+pub struct CtlInfo {
+    pub ctl: PerfCtl,
+    /// The catalog used to name the event.
+    pub catalog: &'static str,
+    /// The definition for the selected event (if any).
+    pub def: Option<EventDef>,
+    /// Names for the set of unit mask bits (if they're defined).
+    pub umasks: Vec<String>,
+    /// Names for the flags that are set (in the same format as
+    /// [crate::spec]).
+    pub flags: Vec<&'static str>,
+}
+impl CtlInfo {
+    /// Returns true if any reserved bits are set.
+    pub fn has_reserved(&self) -> bool {
+        self.ctl.reserved() != 0
+    }
+}
+
+impl PerfCtl {
+    /// Decode this value, naming the event and unit mask bits with some
+    /// [Catalog].
+    ///
+    /// ```
+    /// use lamina::catalog::ZEN2;
+    /// use lamina::pmc::PerfCtl;
+    ///
+    /// // Loads and stores dispatched, edge-triggered with cmask=2 inverted
+    /// let info = PerfCtl(0x02c5_0329).decode(&ZEN2);
+    /// assert_eq!(info.def.unwrap().name, "ls_dispatch");
+    /// assert_eq!(info.umasks, ["ld_dispatch", "store_dispatch"]);
+    /// assert_eq!(info.flags, ["en", "edge", "inv", "user"]);
+    /// assert!(!info.has_reserved());
+    /// assert_eq!(info.to_string(), "\
+    /// PERF_CTL 0x0000000002c50329 (Zen 2)
+    ///   event:  0x029 ls_dispatch - Load/store dispatch
+    ///   umask:  0x03 [ld_dispatch, store_dispatch]
+    ///   cmask:  2
+    ///   flags:  en, edge, inv, user");
+    /// ```
+    pub fn decode(&self, catalog: &Catalog) -> CtlInfo {
+        let umask = self.unit_mask() as u8;
+        let def = catalog.by_select(self.event_select() as u16).copied();
+
+        let mut umasks = Vec::new();
+        if let Some(def) = def {
+            match def.umasks.iter().find(|m| m.mask == umask) {
+                Some(m) if umask != 0 => umasks.push(m.name.to_string()),
+                _ => for bit in (0..8).filter(|b| umask & (1 << b) != 0) {
+                    match def.umasks.iter().find(|m| m.mask == 1 << bit) {
+                        Some(m) => umasks.push(m.name.to_string()),
+                        None => umasks.push(format!("bit{}", bit)),
+                    }
+                },
+            }
+        }
+
+        let mut flags = Vec::new();
+        let bits = [
+            (self.en(), "en"), (self.int(), "int"),
+            (self.edge(), "edge"), (self.inv(), "inv"),
+            (self.osuser() & 0b01 != 0, "user"),
+            (self.osuser() & 0b10 != 0, "os"),
+            (self.hostguest() & 0b10 != 0, "host"),
+            (self.hostguest() & 0b01 != 0, "guest"),
+        ];
+        for (set, name) in bits.iter() {
+            if *set {
+                flags.push(*name);
+            }
+        }
+        CtlInfo { 
+            ctl: *self, catalog: catalog.uarch.to_str(), def, umasks, flags 
+        }
+    }
+}
+
+impl std::fmt::Display for CtlInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ctl = &self.ctl;
+        writeln!(f, "PERF_CTL {:#018x} ({})", ctl.0, self.catalog)?;
+        match &self.def {
+            Some(def) => writeln!(f, "  event:  {:#05x} {} - {}", 
+                ctl.event_select(), def.name, def.desc)?,
+            None => writeln!(f, "  event:  {:#05x} (unknown)", 
+                ctl.event_select())?,
+        }
+        if self.umasks.is_empty() {
+            writeln!(f, "  umask:  {:#04x}", ctl.unit_mask())?;
+        } else {
+            writeln!(f, "  umask:  {:#04x} [{}]", ctl.unit_mask(), 
+                self.umasks.join(", "))?;
+        }
+        writeln!(f, "  cmask:  {}", ctl.count_mask())?;
+        write!(f, "  flags:  {}", self.flags.join(", "))?;
+        if self.has_reserved() {
+            write!(f, "\n  warning: reserved bits are set ({:#018x})", 
+                ctl.reserved())?;
+        }
+        Ok(())
+    }
+}
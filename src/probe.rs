@@ -0,0 +1,292 @@
+//! Named probes for measuring the size of different structures with a
+//! [Sweep] over Henry Wong's gadget (see [crate::capacity]).
+//!
+//! Each [Probe] is a choice of padding instructions (and the dependencies
+//! between them and the two high-latency loads), along with a sweep
+//! configuration and the documented size of the structure on Zen 2.
+//!
+//! ```no_run
+//! use lamina::probe;
+//!
+//! fn main() -> Result<(), lamina::Error> {
+//!     lamina::util::pin_to_core(0)?;
+//!     let probe = probe::Probe::get("fp_prf").unwrap();
+//!     let res = probe.run()?;
+//!     probe.report(&res);
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Note that measured values are often lower than the documented size:
+//! some entries in register files hold architectural state, and the loads
+//! in the gadget also occupy entries in some structures.
+//!
+
+use std::ops::RangeInclusive;
+
+use dynasmrt::{ dynasm, DynasmApi, DynasmLabelApi };
+
+use crate::builder::Asm;
+use crate::capacity::{ Sweep, SweepResults };
+use crate::error::Error;
+
+type Err<T> = Result<T, Error>;
+
+/// A function which emits a single padding instruction (or a group of
+/// instructions counted as one), given the number of padding instructions
+/// emitted before it. This count isn't reset between gadgets (or between
+/// tests in a sweep), so it only distinguishes nearby instructions.
+pub type PadFn = fn(&mut Asm, usize);
+
+/// A sweep measuring some particular structure.
+#[derive(Clone, Debug)]
+pub struct Probe {
+    pub name: &'static str,
+    /// Description of the structure being measured.
+    pub desc: &'static str,
+    /// The documented size of the structure on Zen 2 (if any).
+    pub zen2: Option<usize>,
+    /// The number of padding instructions after each load.
+    pub pads: RangeInclusive<usize>,
+    /// The number of times the gadget is unrolled within the loop.
+    pub unroll: usize,
+    /// The number of loop iterations.
+    pub iters: usize,
+    /// The number of measurements taken for each number of padding
+    /// instructions.
+    pub samples: usize,
+    /// Emits padding after the first load (in RDI).
+    pub body_a: PadFn,
+    /// Emits padding after the second load (in RSI).
+    pub body_b: PadFn,
+}
+impl Probe {
+    /// Return the probe with some name.
+    pub fn get(name: &str) -> Option<&'static Probe> {
+        PROBES.iter().find(|p| p.name == name)
+    }
+
+    /// Return the [Sweep] for this probe.
+    pub fn sweep(&self) -> Sweep {
+        Sweep::new(self.name)
+            .pads(self.pads.clone())
+            .unroll(self.unroll)
+            .iters(self.iters)
+            .samples(self.samples)
+    }
+
+    /// Run the sweep for this probe.
+    pub fn run(&self) -> Err<SweepResults> {
+        let (mut idx_a, mut idx_b) = (0, 0);
+        self.sweep().run(
+            |asm| { (self.body_a)(asm, idx_a); idx_a += 1; },
+            |asm| { (self.body_b)(asm, idx_b); idx_b += 1; },
+        )
+    }
+
+    /// Print results (see [SweepResults::report]), followed by the
+    /// documented size on Zen 2 (on stderr).
+    pub fn report(&self, res: &SweepResults) {
+        res.report();
+        match self.zen2 {
+            Some(size) => eprintln!("[*] {}: documented size on Zen 2: {}",
+                self.name, size),
+            None => eprintln!("[*] {}: size on Zen 2 isn't documented",
+                self.name),
+        }
+    }
+}
+
+fn nop(asm: &mut Asm, _: usize) { dynasm!(asm ; nop); }
+
+fn add_r13(asm: &mut Asm, _: usize) { dynasm!(asm ; add rax, r13); }
+
+fn store_rsi(asm: &mut Asm, _: usize) { dynasm!(asm ; mov [rsi + 8], rsi); }
+fn store_rdi(asm: &mut Asm, _: usize) { dynasm!(asm ; mov [rdi + 8], rdi); }
+
+fn load_rdi(asm: &mut Asm, _: usize) { dynasm!(asm ; mov rax, [rdi + 64]); }
+fn load_rsi(asm: &mut Asm, _: usize) { dynasm!(asm ; mov rbx, [rsi + 64]); }
+
+fn add_rdi(asm: &mut Asm, _: usize) { dynasm!(asm ; add rax, rdi); }
+fn add_rsi(asm: &mut Asm, _: usize) { dynasm!(asm ; add rbx, rsi); }
+
+fn movq_rdi(asm: &mut Asm, _: usize) { dynasm!(asm ; movq xmm0, rdi); }
+fn movq_rsi(asm: &mut Asm, _: usize) { dynasm!(asm ; movq xmm1, rsi); }
+
+fn addps(asm: &mut Asm, _: usize) { dynasm!(asm ; addps xmm2, xmm3); }
+
+fn jcc(asm: &mut Asm, _: usize) { dynasm!(asm ; jo >skip ; skip:); }
+
+fn test(asm: &mut Asm, _: usize) { dynasm!(asm ; test rax, rax); }
+
+fn test_jcc(asm: &mut Asm, _: usize) {
+    dynasm!(asm ; test rax, rax ; jo >skip ; skip:);
+}
+
+/// Each load touches a different cache line in the page being loaded by
+/// the pointer chase (skipping the line with the pointer).
+fn miss_rdi(asm: &mut Asm, idx: usize) {
+    let off = (64 * (1 + idx % 63)) as i32;
+    dynasm!(asm ; mov rax, [rdi + off]);
+}
+fn miss_rsi(asm: &mut Asm, idx: usize) {
+    let off = (64 * (1 + idx % 63)) as i32;
+    dynasm!(asm ; mov rbx, [rsi + off]);
+}
+
+/// Reorder buffer (see `bin/rob.rs`).
+pub const ROB: Probe = Probe {
+    name: "rob",
+    desc: "reorder buffer",
+    zen2: Some(224),
+    pads: 0..=256, unroll: 32, iters: 0x80, samples: 512,
+    body_a: nop, body_b: nop,
+};
+
+/// Integer physical register file.
+///
+/// You can reproduce this with other instructions that consume entries in
+/// the integer PRF. This appears to measure the portion of
+/// [speculatively-consumed] physical register file entries.
+///
+/// AMD's Software Optimization Guide (I'm looking at document 56305,
+/// Rev. 3.02, from March 2020) says the following in section 2.10.3
+/// "Retire Control Unit", on page 34:
+///
+/// > The retire control unit also manages internal integer register
+/// > mapping and renaming. The integer physical register file (PRF)
+/// > consists of 180 registers, with up to 38 per thread mapped to
+/// > architectural state or microarchitectural temporary state. The
+/// > remaining registers are available for out-of-order renames.
+pub const PRF: Probe = Probe {
+    name: "prf",
+    desc: "integer physical register file",
+    zen2: Some(180),
+    pads: 0..=256, unroll: 256, iters: 0x10, samples: 1024,
+    body_a: add_r13, body_b: add_r13,
+};
+
+/// Store queue.
+///
+/// It seems like I get the best results when using RDI/RSI for stores.
+///
+/// You can use other pointers too, but I don't exactly understand how to
+/// interpret the large swings in the graph around 46-48 instructions (also
+/// seems to happen with SFENCE).
+pub const STQ: Probe = Probe {
+    name: "stq",
+    desc: "store queue",
+    zen2: Some(48),
+    pads: 0..=64, unroll: 512, iters: 0x10, samples: 1024,
+    body_a: store_rsi, body_b: store_rdi,
+};
+
+/// Load queue.
+///
+/// It seems like the easiest way to do this is to do more loads with RDI
+/// and RSI.
+pub const LDQ: Probe = Probe {
+    name: "ldq",
+    desc: "load queue",
+    zen2: Some(44),
+    pads: 0..=64, unroll: 128, iters: 0x40, samples: 1024,
+    body_a: load_rdi, body_b: load_rsi,
+};
+
+/// Integer scheduler queues.
+///
+/// Padding depends on the most recent load, so it can't leave the
+/// scheduler until the load completes. Zen 2 has four 16-entry ALU
+/// schedulers (the AGU scheduler isn't used here).
+pub const INT_SCHED: Probe = Probe {
+    name: "int_sched",
+    desc: "integer (ALU) scheduler queues",
+    zen2: Some(64),
+    pads: 0..=128, unroll: 64, iters: 0x40, samples: 512,
+    body_a: add_rdi, body_b: add_rsi,
+};
+
+/// Floating-point scheduler.
+///
+/// Padding moves the result of the most recent load into an XMM register,
+/// so it can't leave the scheduler until the load completes. Ops may also
+/// wait in the non-scheduling queue in front of the scheduler, so the step
+/// may appear later than the documented size.
+pub const FP_SCHED: Probe = Probe {
+    name: "fp_sched",
+    desc: "floating-point scheduler",
+    zen2: Some(36),
+    pads: 0..=128, unroll: 64, iters: 0x40, samples: 512,
+    body_a: movq_rdi, body_b: movq_rsi,
+};
+
+/// Floating-point/vector physical register file.
+///
+/// Each ADDPS allocates a new register, but doesn't depend on either load.
+pub const FP_PRF: Probe = Probe {
+    name: "fp_prf",
+    desc: "floating-point/vector physical register file",
+    zen2: Some(160),
+    pads: 0..=256, unroll: 64, iters: 0x40, samples: 512,
+    body_a: addps, body_b: addps,
+};
+
+/// Tracking for in-flight branches.
+///
+/// Padding is a not-taken conditional branch (the overflow flag is always
+/// clear after the loop condition).
+pub const BRANCH: Probe = Probe {
+    name: "branch",
+    desc: "in-flight branches (branch tag/order buffer)",
+    zen2: None,
+    pads: 0..=128, unroll: 64, iters: 0x40, samples: 512,
+    body_a: jcc, body_b: jcc,
+};
+
+/// Renamed flags.
+///
+/// TEST only writes the flags, so it doesn't allocate a general-purpose
+/// register.
+pub const FLAGS: Probe = Probe {
+    name: "flags",
+    desc: "flag register renames",
+    zen2: None,
+    pads: 0..=256, unroll: 64, iters: 0x40, samples: 512,
+    body_a: test, body_b: test,
+};
+
+/// Fused compare-and-branch ops.
+///
+/// Each padding instruction is a TEST and a not-taken conditional branch,
+/// which are fused into a single op (and a single reorder buffer entry).
+/// This isn't a distinct structure: the step is at whichever of [ROB] or
+/// [BRANCH] is smaller, so this is a cross-check for those probes (and the
+/// documented size is the size of the reorder buffer).
+pub const FUSED: Probe = Probe {
+    name: "fused",
+    desc: "reorder buffer or in-flight branches (fused compare-and-branch)",
+    zen2: Some(224),
+    pads: 0..=256, unroll: 32, iters: 0x80, samples: 512,
+    body_a: test_jcc, body_b: test_jcc,
+};
+
+/// Outstanding L1D misses (miss address buffers).
+///
+/// Each load uses the pointer from the most recent pointer chase (in RDI
+/// or RSI) as its base, and misses in a different line of the same page.
+/// The loads don't depend on each other: loads after the first pointer
+/// chase wait for it, and are then outstanding at the same time as the
+/// second pointer chase.
+pub const MAB: Probe = Probe {
+    name: "mab",
+    desc: "outstanding L1D misses (miss address buffers)",
+    zen2: Some(22),
+    pads: 0..=48, unroll: 32, iters: 0x40, samples: 512,
+    body_a: miss_rdi, body_b: miss_rsi,
+};
+
+/// All of the named probes.
+pub const PROBES: &[Probe] = &[
+    ROB, PRF, STQ, LDQ, INT_SCHED, FP_SCHED, FP_PRF, BRANCH, FLAGS, FUSED,
+    MAB,
+];
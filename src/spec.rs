@@ -0,0 +1,361 @@
+//! Parsing perf-style event specifications.
+//!
+//! An [Event] or a [PerfCtl] can be written as a string (i.e. in a
+//! configuration file or on the command line). A specification is a list
+//! of comma-separated terms:
+//!
+//! - An event name (i.e. `ex_ret_instr` or `ls_dispatch.ld_dispatch`),
+//!   looked up in the installed catalog (see [Catalog::install]) or the
+//!   catalog for the running CPU
+//! - A raw event `rNNN`, where `NNN` is a hexadecimal `PERF_CTL` value
+//!   with the event select and unit mask (i.e. `r0c0` or `r2ae`)
+//! - `event=N` and `umask=N`
+//! - `merge`, or the name of a Linux software event (i.e. `task-clock`)
+//!
+//! A [PerfCtl] specification may also use these terms:
+//!
+//! - `cmask=N`, the count mask
+//! - `inv`, `edge`, `int`, and `en` (enabled by default)
+//! - `user` and `os`, for counting in user mode and/or OS mode (the default
+//!   is `user` only)
+//! - `host` and `guest`, for counting only host and/or guest events (the
+//!   default is to count both)
+//!
+//! Flags may also have a value (i.e. `inv=0` or `os=1`). Numbers are either
+//! decimal or hexadecimal (with a `0x` prefix).
+//!
+//! ```
+//! use lamina::event::Event;
+//! use lamina::pmc::PerfCtl;
+//!
+//! let e: Event = "r2ae".parse().unwrap();
+//! assert_eq!(e, Event::Undefined(0xae, 0x02));
+//! assert_eq!(e.to_string(), "event=0xae,umask=0x02");
+//!
+//! let ctl: PerfCtl = "event=0xae,umask=0x02,cmask=1,inv,edge".parse()
+//!     .unwrap();
+//! assert_eq!(ctl.count_mask(), 1);
+//! assert_eq!(ctl.to_string().parse::<PerfCtl>().unwrap(), ctl);
+//! ```
+//!
+//! Named [Event] variants are displayed with their names from the Zen 2
+//! catalog, but names are always parsed into [Event::Undefined] (with the
+//! same event select and unit mask). Since names are parsed with the
+//! catalog for the running CPU, an event is only displayed with its name
+//! when the name resolves to the same event select and unit mask;
+//! otherwise, the raw event select and unit mask are used.
+//!
+//! ```
+//! use lamina::event::Event;
+//! use lamina::pmc::PerfCtl;
+//!
+//! // This holds whether or not the running CPU is a Zen 2 CPU
+//! for e in [Event::ExRetInstr(0x00), Event::LsDispatch(0x01)] {
+//!     let parsed: Event = e.to_string().parse().unwrap();
+//!     assert_eq!(parsed.convert(), e.convert());
+//! }
+//!
+//! let ctl = PerfCtl::new_merge(false);
+//! assert_eq!(ctl.to_string(), "merge,en=0");
+//! assert_eq!(ctl.to_string().parse::<PerfCtl>().unwrap(), ctl);
+//! ```
+//!
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::catalog::{ Catalog, ZEN2 };
+use crate::event::{ Event, SoftwareEvent };
+use crate::pmc::{ PerfCtl, OSUserBits, HostGuestBits };
+use crate::error::Error;
+
+/// The terms in a specification.
+#[derive(Default)]
+struct Spec {
+    event: Option<Event>,
+    select: Option<u16>,
+    umask: Option<u8>,
+    cmask: Option<u8>,
+    inv: Option<bool>,
+    edge: Option<bool>,
+    int: Option<bool>,
+    en: Option<bool>,
+    user: Option<bool>,
+    os: Option<bool>,
+    host: Option<bool>,
+    guest: Option<bool>,
+}
+
+impl Spec {
+    fn parse(s: &str) -> Result<Self, Error> {
+        let err = |reason: String| Error::InvalidSpec {
+            spec: s.to_string(), reason
+        };
+        let mut spec = Spec::default();
+        for term in s.split(',').map(str::trim) {
+            let (key, val) = match term.split_once('=') {
+                Some((key, val)) => (key.trim(), Some(val.trim())),
+                None => (term, None),
+            };
+            let num = |max: u64| -> Result<u64, Error> {
+                let val = val.ok_or_else(|| {
+                    err(format!("'{}' needs a value", key))
+                })?;
+                parse_num(val).filter(|v| *v <= max).ok_or_else(|| {
+                    err(format!("invalid value '{}' for '{}'", val, key))
+                })
+            };
+            let flag = || -> Result<Option<bool>, Error> {
+                match val {
+                    None | Some("1") => Ok(Some(true)),
+                    Some("0") => Ok(Some(false)),
+                    Some(val) => Err(err(format!(
+                        "invalid value '{}' for '{}'", val, key
+                    ))),
+                }
+            };
+            match key {
+                "" => return Err(err("empty term".to_string())),
+                "event" => spec.select = Some(num(0xfff)? as u16),
+                "umask" => spec.umask = Some(num(0xff)? as u8),
+                "cmask" => spec.cmask = Some(num(0xff)? as u8),
+                "inv" => spec.inv = flag()?,
+                "edge" => spec.edge = flag()?,
+                "int" => spec.int = flag()?,
+                "en" => spec.en = flag()?,
+                "user" => spec.user = flag()?,
+                "os" => spec.os = flag()?,
+                "host" => spec.host = flag()?,
+                "guest" => spec.guest = flag()?,
+                _ if val.is_some() => {
+                    return Err(err(format!("unknown term '{}'", key)));
+                },
+                name => {
+                    if spec.event.is_some() {
+                        return Err(err("more than one event".to_string()));
+                    }
+                    spec.event = Some(match parse_raw(name) {
+                        Some(raw) => spec.raw(raw).map_err(err)?,
+                        None => parse_name(name)?,
+                    });
+                },
+            }
+        }
+        Ok(spec)
+    }
+
+    /// Set the terms for a raw `PERF_CTL` value, returning the event.
+    fn raw(&mut self, raw: u64) -> Result<Event, String> {
+        let ctl = PerfCtl(raw as usize);
+        let valid = PerfCtl::EVTSEL_HI_MASK | PerfCtl::EVTSEL_LO_MASK
+            | PerfCtl::UNITMASK_MASK | PerfCtl::CNTMASK_MASK
+            | PerfCtl::INV_MASK | PerfCtl::EDGE_MASK;
+        if raw > usize::MAX as u64 || (ctl.0 & !valid) != 0 {
+            return Err(format!("unsupported bits in raw event {:#x}", raw));
+        }
+        if ctl.count_mask() != 0 {
+            self.cmask = Some(ctl.count_mask() as u8);
+        }
+        if ctl.inv() {
+            self.inv = Some(true);
+        }
+        if ctl.edge() {
+            self.edge = Some(true);
+        }
+        Ok(Event::Undefined(ctl.event_select() as u16,
+            ctl.unit_mask() as u8))
+    }
+
+    /// Returns true if any terms only apply to a [PerfCtl].
+    fn has_ctl_terms(&self) -> bool {
+        self.cmask.is_some() || self.inv.is_some() || self.edge.is_some()
+            || self.int.is_some() || self.en.is_some() || self.user.is_some()
+            || self.os.is_some() || self.host.is_some()
+            || self.guest.is_some()
+    }
+
+    /// Combine the event with any `event` and `umask` terms.
+    fn event(&self) -> Result<Event, String> {
+        match (self.event, self.select, self.umask) {
+            (None, None, _) => Err("no event".to_string()),
+            (Some(e), None, None) => Ok(e),
+            (Some(e @ Event::Merge), ..) | (Some(e @ Event::Software(_)), ..)
+                => Err(format!("'{}' has no event select or unit mask", e)),
+            (e, select, umask) => {
+                let (s, m) = e.map(|e| e.convert()).unwrap_or((0, 0));
+                Ok(Event::Undefined(select.unwrap_or(s), umask.unwrap_or(m)))
+            },
+        }
+    }
+}
+
+/// Parse a decimal or hexadecimal (with a `0x` prefix) number.
+fn parse_num(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parse a raw event (i.e. `r0c0`).
+fn parse_raw(s: &str) -> Option<u64> {
+    let hex = s.strip_prefix('r')?;
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Parse the name of an event.
+fn parse_name(name: &str) -> Result<Event, Error> {
+    if name == "merge" {
+        return Ok(Event::Merge);
+    }
+    if let Some(e) = SoftwareEvent::ALL.iter().find(|e| e.name() == name) {
+        return Ok(Event::Software(*e));
+    }
+    let catalog = match Catalog::installed() {
+        Some(cat) => cat,
+        None => Catalog::native()?,
+    };
+    catalog.lookup(name, 0)
+}
+
+impl FromStr for Event {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec = Spec::parse(s)?;
+        let err = |reason: String| Error::InvalidSpec {
+            spec: s.to_string(), reason
+        };
+        if spec.has_ctl_terms() {
+            return Err(err("only an event and unit mask are allowed here \
+                (see PerfCtl)".to_string()));
+        }
+        spec.event().map_err(err)
+    }
+}
+
+impl FromStr for PerfCtl {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec = Spec::parse(s)?;
+        let err = |reason: String| Error::InvalidSpec {
+            spec: s.to_string(), reason
+        };
+        if spec.event == Some(Event::Merge) {
+            let en = spec.en.unwrap_or(true);
+            let spec = Spec { en: None, ..spec };
+            if spec.has_ctl_terms() || spec.select.is_some()
+                || spec.umask.is_some()
+            {
+                return Err(err("'merge' can't have terms other than 'en'"
+                    .to_string()));
+            }
+            return Ok(PerfCtl::new_merge(en));
+        }
+
+        let mut ctl = PerfCtl::new(spec.event().map_err(err)?, true);
+        ctl.set_count_mask(spec.cmask.unwrap_or(0) as usize);
+        ctl.set_inv(spec.inv.unwrap_or(false));
+        ctl.set_edge(spec.edge.unwrap_or(false));
+        ctl.set_int(spec.int.unwrap_or(false));
+        ctl.set_en(spec.en.unwrap_or(true));
+        if spec.user.is_some() || spec.os.is_some() {
+            ctl.set_osuser(match (spec.user, spec.os) {
+                (Some(true), Some(true)) => OSUserBits::All,
+                (Some(true), _) => OSUserBits::User,
+                (_, Some(true)) => OSUserBits::OS,
+                _ => OSUserBits::None,
+            });
+        }
+        ctl.set_hostguest(match (spec.host, spec.guest) {
+            (Some(true), Some(true)) => HostGuestBits::SVMEAll,
+            (Some(true), _) => HostGuestBits::SVMEHost,
+            (_, Some(true)) => HostGuestBits::SVMEGuest,
+            _ => HostGuestBits::All,
+        });
+        Ok(ctl)
+    }
+}
+
+/// Parse a list of specifications, returning each [Event] and its
+/// [PerfCtl] value (i.e. for [crate::mux::Multiplexer::from_ctls]).
+///
+/// The name of an event is kept when there are no other terms.
+pub fn parse_events<S: AsRef<str>>(specs: &[S])
+    -> Result<(Vec<Event>, Vec<PerfCtl>), Error>
+{
+    let mut events = Vec::new();
+    let mut ctls = Vec::new();
+    for spec in specs.iter().map(|s| s.as_ref()) {
+        let ctl: PerfCtl = spec.parse()?;
+        events.push(spec.parse::<Event>().unwrap_or_else(|_| ctl.event()));
+        ctls.push(ctl);
+    }
+    Ok((events, ctls))
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (select, umask) = self.convert();
+        match self {
+            Event::Merge => return write!(f, "merge"),
+            Event::Software(e) => return write!(f, "{}", e.name()),
+            Event::Undefined(..) => {},
+            _ => if let Some((def, m)) = ZEN2.resolve(self) {
+                let name = match m {
+                    Some(m) => format!("{}.{}", def.name, m.name),
+                    None if umask == def.default_umask => def.name.to_string(),
+                    None => format!("{},umask={:#04x}", def.name, umask),
+                };
+                // Names are parsed with a different catalog, and might not
+                // resolve to the same event (or at all)
+                if name.parse::<Event>()
+                    .is_ok_and(|e| e.convert() == (select, umask))
+                {
+                    return write!(f, "{}", name);
+                }
+            },
+        }
+        write!(f, "event={:#04x},umask={:#04x}", select, umask)
+    }
+}
+
+impl fmt::Display for PerfCtl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == PerfCtl::new_merge(self.en()) {
+            return write!(f, "merge{}", if self.en() { "" } else { ",en=0" });
+        }
+        write!(f, "event={:#04x},umask={:#04x}", self.event_select(),
+            self.unit_mask())?;
+        if self.count_mask() != 0 {
+            write!(f, ",cmask={}", self.count_mask())?;
+        }
+        if self.inv() {
+            write!(f, ",inv")?;
+        }
+        if self.edge() {
+            write!(f, ",edge")?;
+        }
+        if self.int() {
+            write!(f, ",int")?;
+        }
+        if !self.en() {
+            write!(f, ",en=0")?;
+        }
+        match self.osuser() {
+            0b00 => write!(f, ",user=0")?,
+            0b10 => write!(f, ",os")?,
+            0b11 => write!(f, ",user,os")?,
+            _ => {},
+        }
+        match self.hostguest() {
+            0b01 => write!(f, ",guest")?,
+            0b10 => write!(f, ",host")?,
+            0b11 => write!(f, ",host,guest")?,
+            _ => {},
+        }
+        Ok(())
+    }
+}
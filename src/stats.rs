@@ -0,0 +1,213 @@
+//! Summary statistics for sets of measurements.
+//!
+//! The [Samples] trait is implemented for slices of [usize], so it can be
+//! used on the data collected by [crate::PMCTest] (see
+//! [crate::PMCResults::summary]) and on any plain `Vec<usize>` of results:
+//!
+//! ```
+//! use lamina::stats::Samples;
+//!
+//! let res: Vec<usize> = vec![10, 11, 10, 12, 10, 250];
+//! assert_eq!(res.median(), 10.5);
+//! assert_eq!(res.filter_outliers(3.0), vec![10, 11, 10, 12, 10]);
+//! println!("{}", res.summary());
+//! ```
+//!
+//! All of the functions here panic when given an empty set of samples.
+//! The minimum and maximum are only available in a [Summary] (slices
+//! already provide `iter().min()` and `iter().max()`).
+//!
+
+use std::collections::BTreeMap;
+use serde::Serialize;
+
+/// z-score for a 95% confidence interval.
+pub const Z_95: f64 = 1.959964;
+/// z-score for a 99% confidence interval.
+pub const Z_99: f64 = 2.575829;
+
+/// Scale factor relating the median absolute deviation to the standard
+/// deviation (for normally-distributed data).
+const MAD_SCALE: f64 = 1.4826;
+
+/// Statistics computed over a set of samples.
+pub trait Samples {
+    /// The most frequent sample (the smallest, if there are many).
+    fn mode(&self) -> usize;
+    /// A histogram mapping each sample to the number of times it occurs.
+    fn histogram(&self) -> BTreeMap<usize, usize>;
+    /// The arithmetic mean.
+    fn mean(&self) -> f64;
+    /// The sample variance.
+    fn variance(&self) -> f64;
+    /// The sample standard deviation.
+    fn stddev(&self) -> f64;
+    /// The median.
+    fn median(&self) -> f64;
+    /// The `p`th percentile (where `0.0 <= p <= 100.0`), linearly
+    /// interpolating between the nearest samples.
+    fn percentile(&self, p: f64) -> f64;
+    /// The median absolute deviation from the median.
+    fn mad(&self) -> f64;
+    /// Return the samples which are within `k` (scaled) median absolute
+    /// deviations from the median (typically, `k` is around 3.0).
+    ///
+    /// When more than half of the samples are identical, the MAD is zero
+    /// and only samples equal to the median are kept.
+    fn filter_outliers(&self, k: f64) -> Vec<usize>;
+    /// A confidence interval for the mean with some z-score (i.e. [Z_95]),
+    /// using the normal approximation.
+    fn confidence_interval(&self, z: f64) -> (f64, f64);
+    /// Compute a [Summary] of all the statistics above.
+    fn summary(&self) -> Summary;
+}
+
+impl Samples for [usize] {
+    fn mode(&self) -> usize {
+        assert!(!self.is_empty(), "no samples");
+        let (val, _) = self.histogram().into_iter()
+            .fold((0, 0), |(v, c), (x, n)| if n > c { (x, n) } else { (v, c) });
+        val
+    }
+
+    fn histogram(&self) -> BTreeMap<usize, usize> {
+        let mut map = BTreeMap::new();
+        for value in self.iter() {
+            *map.entry(*value).or_insert(0) += 1;
+        }
+        map
+    }
+
+    fn mean(&self) -> f64 {
+        assert!(!self.is_empty(), "no samples");
+        self.iter().map(|x| *x as f64).sum::<f64>() / self.len() as f64
+    }
+
+    fn variance(&self) -> f64 {
+        let mean = self.mean();
+        if self.len() < 2 {
+            return 0.0;
+        }
+        self.iter().map(|x| (*x as f64 - mean).powi(2)).sum::<f64>()
+            / (self.len() - 1) as f64
+    }
+
+    fn stddev(&self) -> f64 {
+        self.variance().sqrt()
+    }
+
+    fn median(&self) -> f64 {
+        self.percentile(50.0)
+    }
+
+    fn percentile(&self, p: f64) -> f64 {
+        assert!(!self.is_empty(), "no samples");
+        assert!((0.0..=100.0).contains(&p));
+        let mut sorted = self.to_vec();
+        sorted.sort_unstable();
+        percentile_sorted(&sorted, p)
+    }
+
+    fn mad(&self) -> f64 {
+        let median = self.median();
+        let mut dev: Vec<f64> = self.iter()
+            .map(|x| (*x as f64 - median).abs())
+            .collect();
+        dev.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let mid = dev.len() / 2;
+        if dev.len() % 2 == 1 {
+            dev[mid]
+        } else {
+            (dev[mid - 1] + dev[mid]) / 2.0
+        }
+    }
+
+    fn filter_outliers(&self, k: f64) -> Vec<usize> {
+        let median = self.median();
+        let limit = k * MAD_SCALE * self.mad();
+        self.iter().copied()
+            .filter(|x| (*x as f64 - median).abs() <= limit)
+            .collect()
+    }
+
+    fn confidence_interval(&self, z: f64) -> (f64, f64) {
+        let mean = self.mean();
+        let err = z * self.stddev() / (self.len() as f64).sqrt();
+        (mean - err, mean + err)
+    }
+
+    fn summary(&self) -> Summary {
+        let mut sorted = self.to_vec();
+        sorted.sort_unstable();
+        Summary {
+            n: self.len(),
+            min: sorted[0] as f64,
+            max: sorted[sorted.len() - 1] as f64,
+            mode: self.mode() as f64,
+            mean: self.mean(),
+            median: percentile_sorted(&sorted, 50.0),
+            stddev: self.stddev(),
+            mad: self.mad(),
+            p05: percentile_sorted(&sorted, 5.0),
+            p95: percentile_sorted(&sorted, 95.0),
+            ci95: self.confidence_interval(Z_95),
+        }
+    }
+}
+
+/// Compute a percentile over some sorted set of samples.
+fn percentile_sorted(sorted: &[usize], p: f64) -> f64 {
+    let rank = (p / 100.0) * (sorted.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    let frac = rank - lo as f64;
+    sorted[lo] as f64 + (sorted[hi] as f64 - sorted[lo] as f64) * frac
+}
+
+/// Summary statistics for a set of samples (see [Samples::summary]).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    /// The number of samples.
+    pub n: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mode: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64,
+    /// Median absolute deviation.
+    pub mad: f64,
+    /// 5th percentile.
+    pub p05: f64,
+    /// 95th percentile.
+    pub p95: f64,
+    /// 95% confidence interval for the mean.
+    pub ci95: (f64, f64),
+}
+impl Summary {
+    /// Return a copy with all values divided by some factor (i.e. for
+    /// normalizing to the number of loop iterations in a test).
+    pub fn scaled(&self, div: f64) -> Self {
+        Self {
+            n: self.n,
+            min: self.min / div,
+            max: self.max / div,
+            mode: self.mode / div,
+            mean: self.mean / div,
+            median: self.median / div,
+            stddev: self.stddev / div,
+            mad: self.mad / div,
+            p05: self.p05 / div,
+            p95: self.p95 / div,
+            ci95: (self.ci95.0 / div, self.ci95.1 / div),
+        }
+    }
+}
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "min={:.3} med={:.3} mode={:.3} mean={:.3} max={:.3} \
+            sd={:.3} mad={:.3} ci95=[{:.3}, {:.3}]",
+            self.min, self.median, self.mode, self.mean, self.max,
+            self.stddev, self.mad, self.ci95.0, self.ci95.1
+        )
+    }
+}
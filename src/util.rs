@@ -1,5 +1,6 @@
 //! Miscellaneous helper functions.
 
+use crate::error::Error;
 use dynasmrt::{ ExecutableBuffer, AssemblyOffset };
 use iced_x86::{ 
     Decoder, DecoderOptions, Instruction, Formatter, IntelFormatter 
@@ -20,11 +21,22 @@ pub fn clflush(len: usize, p: *const [u8; 64]) {
 
 
 /// Pin the current process to a particular core.
-pub fn pin_to_core(core_id: usize) {
+pub fn pin_to_core(core_id: usize) -> Result<(), Error> {
     let mut cpuset = nix::sched::CpuSet::new();
     let this_pid = nix::unistd::Pid::from_raw(0);
-    cpuset.set(core_id).unwrap();
-    nix::sched::sched_setaffinity(this_pid, &cpuset).unwrap();
+    cpuset.set(core_id)
+        .and_then(|_| nix::sched::sched_setaffinity(this_pid, &cpuset))
+        .map_err(|errno| Error::Affinity { core: core_id, errno })
+}
+
+/// Return the set of cores that the current thread can run on.
+pub fn affinity() -> Result<Vec<usize>, Error> {
+    let this_pid = nix::unistd::Pid::from_raw(0);
+    let cpuset = nix::sched::sched_getaffinity(this_pid)
+        .map_err(|errno| Error::GetAffinity { errno })?;
+    Ok((0..nix::sched::CpuSet::count())
+        .filter(|idx| cpuset.is_set(*idx).unwrap_or(false))
+        .collect())
 }
 
 pub fn disas_inst(buf: &[u8]) -> String {